@@ -0,0 +1,2902 @@
+use ark_bls12_381::Fr;
+use ark_ff::{BigInteger, Field, One, PrimeField, Zero};
+use ark_relations::lc;
+use ark_relations::r1cs::{
+    ConstraintSynthesizer, ConstraintSystem, ConstraintSystemRef, LinearCombination, OptimizationGoal, SynthesisError,
+    SynthesisMode, Variable,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use crate::commitment;
+use crate::gadgets;
+use crate::memory::{self, Access, AccessVars, NULL_ADDRESS};
+use crate::public_inputs::{PublicInputLayout, PublicInputs};
+use crate::vm::{GasCosts, InputTapes, Instruction, MemoryModel, Opcode, ProvableState, CARRY_FLAG, EQUAL_FLAG, LESS_FLAG};
+
+/// Width of a VM word in bits.
+pub const WORD_BITS: usize = 32;
+
+/// Shift amounts are taken modulo the word size, i.e. from their low five bits.
+const SHIFT_BITS: usize = 5;
+
+/// Width of `ProvableState::flags` in bits.
+const FLAG_BITS: usize = 8;
+
+/// Width of an encoded instruction: 8 opcode bits, the operand flag, then the operand.
+const INSTRUCTION_BITS: usize = 9 + WORD_BITS;
+
+/// Number of words packed into one field element of a state encoding.
+const WORDS_PER_ELEMENT: usize = 7;
+
+/// Size limits of the universal circuit.
+///
+/// The circuit is built for these bounds only, never for a particular program, so
+/// one key pair proves every program and execution that fits in them. The program
+/// is padded with HALT to `max_program` instructions and the trace is padded to
+/// `max_steps + 1` states by repeating its final state, and CALL may nest
+/// `max_call_depth` deep. The heap is checked through a memory log (see `memory`),
+/// so it spans every word address at a cost that does not depend on its size; only
+/// the memory image it starts out with is limited, to `max_image` cells. Each input
+/// tape holds at most `max_input` words and the output tape `max_output`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBounds {
+    pub max_steps: usize,
+    pub max_stack: usize,
+    pub max_program: usize,
+    pub max_call_depth: usize,
+    pub max_image: usize,
+    pub max_input: usize,
+    pub max_output: usize,
+}
+
+impl Default for CircuitBounds {
+    fn default() -> Self {
+        Self {
+            max_steps: 64,
+            max_stack: 8,
+            max_program: 32,
+            max_call_depth: 4,
+            max_image: 16,
+            max_input: 16,
+            max_output: 8,
+        }
+    }
+}
+
+/// Why a program, its execution or a public layout does not fit an
+/// `ExecutionCircuit`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CircuitError {
+    /// The bounds allow no steps or no stack slots.
+    EmptyBounds,
+    ProgramTooLong { len: usize, max: usize },
+    /// The trace does not even hold an initial state.
+    EmptyTrace,
+    /// The execution does not halt within `max` steps.
+    TooManySteps { max: usize },
+    StackTooDeep { depth: usize, max: usize },
+    CallsTooDeep { depth: usize, max: usize },
+    InputTooLong { secret: bool, len: usize, max: usize },
+    ImageTooLarge { cells: usize, max: usize },
+    /// The heap of the initial state is not the memory image.
+    ImageMismatch,
+    /// A heap cell is listed twice in one part of the public layout.
+    RepeatedPublicCell { address: u32 },
+    OutputTooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for CircuitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CircuitError::EmptyBounds => write!(f, "The circuit must allow at least one step and one stack slot"),
+            CircuitError::ProgramTooLong { len, max } => {
+                write!(f, "Program has {} instructions, the circuit supports at most {}", len, max)
+            }
+            CircuitError::EmptyTrace => write!(f, "Trace is empty, no initial state"),
+            CircuitError::TooManySteps { max } => write!(f, "Execution needs more than {} steps", max),
+            CircuitError::StackTooDeep { depth, max } => {
+                write!(f, "Stack grows to {} elements, the circuit supports at most {}", depth, max)
+            }
+            CircuitError::CallsTooDeep { depth, max } => {
+                write!(f, "Calls nest {} deep, the circuit supports at most {}", depth, max)
+            }
+            CircuitError::InputTooLong { secret, len, max } => write!(
+                f,
+                "{} input tape has {} words, the circuit supports at most {}",
+                if *secret { "Secret" } else { "Public" },
+                len,
+                max
+            ),
+            CircuitError::ImageTooLarge { cells, max } => {
+                write!(f, "Memory image has {} cells, the circuit supports at most {}", cells, max)
+            }
+            CircuitError::ImageMismatch => write!(f, "Initial heap does not match the memory image"),
+            CircuitError::RepeatedPublicCell { address } => {
+                write!(f, "Public heap address {} is listed twice", address)
+            }
+            CircuitError::OutputTooLong { len, max } => {
+                write!(f, "Program writes {} words, the circuit supports at most {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CircuitError {}
+
+impl CircuitBounds {
+    /// Checks that `program` and its execution `trace` fit in the bounds.
+    pub fn check(&self, program: &[Instruction], trace: &[ProvableState]) -> Result<(), CircuitError> {
+        if self.max_steps == 0 || self.max_stack == 0 {
+            return Err(CircuitError::EmptyBounds);
+        }
+        if program.len() > self.max_program {
+            return Err(CircuitError::ProgramTooLong {
+                len: program.len(),
+                max: self.max_program,
+            });
+        }
+
+        // Every step past the end of the trace is a HALT, so a trace that uses up all
+        // the steps must have halted on its own
+        let steps = trace.len().checked_sub(1).ok_or(CircuitError::EmptyTrace)?;
+        let halted = trace.windows(2).last().is_some_and(|step| {
+            program
+                .get(step[0].pc as usize)
+                .is_some_and(|instruction| instruction.opcode == Opcode::HALT)
+        });
+        if steps > self.max_steps || (steps == self.max_steps && !halted) {
+            return Err(CircuitError::TooManySteps { max: self.max_steps });
+        }
+
+        for state in trace {
+            if state.stack.len() > self.max_stack {
+                return Err(CircuitError::StackTooDeep {
+                    depth: state.stack.len(),
+                    max: self.max_stack,
+                });
+            }
+            if state.call_stack.len() > self.max_call_depth {
+                return Err(CircuitError::CallsTooDeep {
+                    depth: state.call_stack.len(),
+                    max: self.max_call_depth,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Pads `trace` to `max_steps + 1` states by repeating its final state, which is
+    /// what a halted machine keeps doing.
+    pub fn pad_trace(&self, trace: &[ProvableState]) -> Vec<ProvableState> {
+        let mut padded = trace.to_vec();
+        if let Some(last) = trace.last() {
+            padded.resize(trace.len().max(self.max_steps + 1), last.clone());
+        }
+        padded
+    }
+
+    /// Encoded instructions of `program`, padded with HALT to `max_program`.
+    pub fn pad_program(&self, program: &[Instruction]) -> Vec<u64> {
+        let mut encoded: Vec<u64> = program.iter().map(Instruction::encode).collect();
+        encoded.resize(encoded.len().max(self.max_program), halt_encoding());
+        encoded
+    }
+
+    /// Describes the bounds to a commitment.
+    pub fn header(&self) -> Vec<Fr> {
+        [
+            self.max_steps,
+            self.max_stack,
+            self.max_program,
+            self.max_call_depth,
+            self.max_image,
+            self.max_input,
+            self.max_output,
+        ]
+            .into_iter()
+            .map(|bound| Fr::from(bound as u64))
+            .collect()
+    }
+
+    /// Stack contents top-first, padded with zeros to `max_stack`.
+    pub fn stack_slots(&self, state: &ProvableState) -> Vec<u32> {
+        let mut slots: Vec<u32> = state.stack.iter().rev().copied().collect();
+        slots.resize(self.max_stack.max(slots.len()), 0);
+        slots
+    }
+
+    /// Return addresses innermost-first, padded with zeros to `max_call_depth`.
+    pub fn call_slots(&self, state: &ProvableState) -> Vec<u32> {
+        let mut slots: Vec<u32> = state.call_stack.iter().rev().copied().collect();
+        slots.resize(self.max_call_depth.max(slots.len()), 0);
+        slots
+    }
+
+    /// Field encoding of a state: `pc + 2^32 sp + 2^64 flags + 2^96 call depth`
+    /// `+ 2^128 public_read + 2^160 secret_read + 2^192 written`, then the gas used,
+    /// the stack slots and the call stack slots, seven to a field element. The heap
+    /// is not part of a state, see `memory`.
+    pub fn encode_state(&self, state: &ProvableState) -> Vec<Fr> {
+        let mut encoding = vec![pack(
+            &[
+                state.pc as u64,
+                state.stack.len() as u64,
+                state.flags as u64,
+                state.call_stack.len() as u64,
+                state.public_read as u64,
+                state.secret_read as u64,
+                state.written as u64,
+            ],
+            WORD_BITS,
+        )];
+        encoding.push(Fr::from(state.gas_used));
+
+        let slots: Vec<u64> = self
+            .stack_slots(state)
+            .into_iter()
+            .take(self.max_stack)
+            .map(u64::from)
+            .collect();
+        encoding.extend(slots.chunks(WORDS_PER_ELEMENT).map(|chunk| pack(chunk, WORD_BITS)));
+
+        let calls: Vec<u64> = self
+            .call_slots(state)
+            .into_iter()
+            .take(self.max_call_depth)
+            .map(u64::from)
+            .collect();
+        encoding.extend(calls.chunks(WORDS_PER_ELEMENT).map(|chunk| pack(chunk, WORD_BITS)));
+
+        encoding
+    }
+}
+
+fn halt_encoding() -> u64 {
+    Instruction {
+        opcode: Opcode::HALT,
+        operand: None,
+    }
+    .encode()
+}
+
+/// `values[0] + 2^bits values[1] + 2^(2 bits) values[2] + ...`
+pub(crate) fn pack(values: &[u64], bits: usize) -> Fr {
+    let shift = Fr::from(2u64).pow([bits as u64]);
+    values
+        .iter()
+        .rev()
+        .fold(Fr::zero(), |packed, &value| packed * shift + Fr::from(value))
+}
+
+/// In-circuit counterpart of `pack`.
+pub(crate) fn pack_lc(parts: impl IntoIterator<Item = LinearCombination<Fr>>, bits: usize) -> LinearCombination<Fr> {
+    let shift = Fr::from(2u64).pow([bits as u64]);
+    let mut coeff = Fr::one();
+    let mut packed = lc!();
+    for part in parts {
+        packed = packed + (coeff, &part);
+        coeff *= shift;
+    }
+    packed
+}
+
+/// Witness variables of one `ProvableState`.
+struct StateVars {
+    pc: Variable,
+    /// Sum of `stack_mask`, so it ranges over `0..=max_stack`.
+    sp: Variable,
+    stack: Vec<Variable>,
+    /// `occupied[k]` is set when stack slot `k` holds a value.
+    occupied: Vec<Variable>,
+    /// Call stack depth and return addresses innermost-first, masked like the stack.
+    csp: Variable,
+    calls: Vec<Variable>,
+    flags: Variable,
+    /// Words read so far from each input tape.
+    public_read: Variable,
+    secret_read: Variable,
+    /// Words written so far to the output tape.
+    written: Variable,
+    gas_used: Variable,
+}
+
+impl StateVars {
+    fn alloc(
+        cs: &ConstraintSystemRef<Fr>,
+        bounds: &CircuitBounds,
+        state: &ProvableState,
+    ) -> Result<Self, SynthesisError> {
+        let pc = cs.new_witness_variable(|| Ok(Fr::from(state.pc)))?;
+
+        let (sp, stack, occupied) =
+            alloc_masked_slots(cs, &bounds.stack_slots(state), state.stack.len(), bounds.max_stack)?;
+        let (csp, calls, _) = alloc_masked_slots(
+            cs,
+            &bounds.call_slots(state),
+            state.call_stack.len(),
+            bounds.max_call_depth,
+        )?;
+
+        let flags = cs.new_witness_variable(|| Ok(Fr::from(state.flags)))?;
+        let public_read = cs.new_witness_variable(|| Ok(Fr::from(state.public_read)))?;
+        let secret_read = cs.new_witness_variable(|| Ok(Fr::from(state.secret_read)))?;
+        let written = cs.new_witness_variable(|| Ok(Fr::from(state.written)))?;
+        let gas_used = cs.new_witness_variable(|| Ok(Fr::from(state.gas_used)))?;
+
+        Ok(Self {
+            pc,
+            sp,
+            stack,
+            occupied,
+            csp,
+            calls,
+            flags,
+            public_read,
+            secret_read,
+            written,
+            gas_used,
+        })
+    }
+
+    /// Variables in the order of `CircuitBounds::encode_state`.
+    fn encode(&self, cs: &ConstraintSystemRef<Fr>) -> Result<Vec<Variable>, SynthesisError> {
+        let header = pack_lc(
+            [
+                lc!() + self.pc,
+                lc!() + self.sp,
+                lc!() + self.flags,
+                lc!() + self.csp,
+                lc!() + self.public_read,
+                lc!() + self.secret_read,
+                lc!() + self.written,
+            ],
+            WORD_BITS,
+        );
+        let mut encoding = vec![cs.new_lc(header)?, self.gas_used];
+
+        for chunk in self.stack.chunks(WORDS_PER_ELEMENT).chain(self.calls.chunks(WORDS_PER_ELEMENT)) {
+            encoding.push(cs.new_lc(pack_lc(chunk.iter().map(|&slot| lc!() + slot), WORD_BITS))?);
+        }
+
+        Ok(encoding)
+    }
+}
+
+/// Allocates the first `capacity` of `slots` for a stack holding `depth` values and
+/// returns its pointer, the slots and their occupancy mask.
+fn alloc_masked_slots(
+    cs: &ConstraintSystemRef<Fr>,
+    slots: &[u32],
+    depth: usize,
+    capacity: usize,
+) -> Result<(Variable, Vec<Variable>, Vec<Variable>), SynthesisError> {
+    // mask[k] = (k < sp) is a monotone run of ones, and every slot it does not cover
+    // is zero. The mask bounds the stack pointer, which is what rejects pops from an
+    // empty stack and pushes onto a full one.
+    let mut sp = lc!();
+    let mut vars = Vec::with_capacity(capacity);
+    let mut occupied = Vec::with_capacity(capacity);
+    let mut previous_mask: Option<Variable> = None;
+    for (k, &value) in slots.iter().take(capacity).enumerate() {
+        let mask = cs.new_witness_variable(|| Ok(Fr::from(k < depth)))?;
+        gadgets::enforce_boolean(cs, mask)?;
+        if let Some(previous) = previous_mask {
+            cs.enforce_constraint(lc!() + mask, lc!() + Variable::One - previous, lc!())?;
+        }
+
+        let slot = cs.new_witness_variable(|| Ok(Fr::from(value)))?;
+        cs.enforce_constraint(lc!() + Variable::One - mask, lc!() + slot, lc!())?;
+
+        sp = sp + mask;
+        vars.push(slot);
+        occupied.push(mask);
+        previous_mask = Some(mask);
+    }
+    Ok((cs.new_lc(sp)?, vars, occupied))
+}
+
+/// The program as witness data: `max_program` encoded instructions, of which the
+/// first `len` belong to the program and the rest are HALT padding.
+struct ProgramVars {
+    entries: Vec<Variable>,
+    len: LinearCombination<Fr>,
+    encoded: Vec<u64>,
+    program_len: usize,
+}
+
+impl ProgramVars {
+    fn alloc(
+        cs: &ConstraintSystemRef<Fr>,
+        bounds: &CircuitBounds,
+        program: &[Instruction],
+    ) -> Result<(Self, Vec<Variable>), SynthesisError> {
+        let encoded = bounds.pad_program(program);
+        let halt = Fr::from(halt_encoding());
+
+        let mut entries = Vec::with_capacity(bounds.max_program);
+        let mut mask = Vec::with_capacity(bounds.max_program);
+        let mut len = lc!();
+        for (j, &instruction) in encoded.iter().take(bounds.max_program).enumerate() {
+            let entry = cs.new_witness_variable(|| Ok(Fr::from(instruction)))?;
+
+            // The mask is a prefix of ones marking the program, everything after it is HALT
+            let included = cs.new_witness_variable(|| Ok(Fr::from(j < program.len())))?;
+            gadgets::enforce_boolean(cs, included)?;
+            if let Some(&previous) = mask.last() {
+                cs.enforce_constraint(lc!() + included, lc!() + Variable::One - previous, lc!())?;
+            }
+            cs.enforce_constraint(
+                lc!() + Variable::One - included,
+                lc!() + entry - (halt, Variable::One),
+                lc!(),
+            )?;
+
+            len = len + included;
+            entries.push(entry);
+            mask.push(included);
+        }
+
+        let program = Self {
+            entries,
+            len,
+            encoded,
+            program_len: program.len(),
+        };
+        Ok((program, mask))
+    }
+
+    /// Encoded instruction at `pc`. Running off the end of the program fetches the
+    /// HALT padding, or the HALT entry past the last one for a full program.
+    fn native(&self, pc: u32) -> u64 {
+        self.encoded.get(pc as usize).copied().unwrap_or_else(halt_encoding)
+    }
+
+    /// Selects the entry at `pc` with a one-hot vector over every entry plus a
+    /// trailing constant HALT.
+    fn fetch(&self, cs: &ConstraintSystemRef<Fr>, pc: Variable, pc_value: u32) -> Result<Variable, SynthesisError> {
+        let mut hits = lc!();
+        let mut index = lc!();
+        let mut selected = lc!();
+
+        for j in 0..=self.entries.len() {
+            let hit = pc_value as usize == j;
+            let hit_var = cs.new_witness_variable(|| Ok(Fr::from(hit)))?;
+            gadgets::enforce_boolean(cs, hit_var)?;
+            hits = hits + hit_var;
+            index += (Fr::from(j as u64), hit_var);
+
+            selected = match self.entries.get(j) {
+                Some(&entry) => {
+                    let value = if hit { Fr::from(self.encoded[j]) } else { Fr::zero() };
+                    selected + gadgets::product(cs, lc!() + hit_var, lc!() + entry, value)?
+                }
+                None => selected + (Fr::from(halt_encoding()), hit_var),
+            };
+        }
+
+        gadgets::enforce_equal(cs, hits, lc!() + Variable::One)?;
+        gadgets::enforce_equal(cs, index, lc!() + pc)?;
+        cs.new_lc(selected)
+    }
+}
+
+/// Words of an input or output tape, padded with zeros to the tape's capacity.
+struct TapeVars {
+    words: Vec<Variable>,
+    /// `mask[j]` is set when the tape holds a word `j`, a prefix of ones like the
+    /// program mask.
+    mask: Vec<Variable>,
+    tape: Vec<u32>,
+}
+
+impl TapeVars {
+    /// Witnesses an input tape of `max_input` words.
+    fn alloc(cs: &ConstraintSystemRef<Fr>, bounds: &CircuitBounds, tape: &[u32]) -> Result<Self, SynthesisError> {
+        let mut words = Vec::with_capacity(bounds.max_input);
+        for j in 0..bounds.max_input {
+            let value = tape.get(j).copied().unwrap_or(0);
+            let word = cs.new_witness_variable(|| Ok(Fr::from(value)))?;
+            gadgets::to_bits(cs, word, value as u64, WORD_BITS)?;
+            words.push(word);
+        }
+        Self::new(cs, words, tape)
+    }
+
+    /// The tape `tape` held by `words`, which are zero past its end.
+    fn new(cs: &ConstraintSystemRef<Fr>, words: Vec<Variable>, tape: &[u32]) -> Result<Self, SynthesisError> {
+        let mut mask: Vec<Variable> = Vec::with_capacity(words.len());
+        for (j, &word) in words.iter().enumerate() {
+            let included = cs.new_witness_variable(|| Ok(Fr::from(j < tape.len())))?;
+            gadgets::enforce_boolean(cs, included)?;
+            if let Some(&previous) = mask.last() {
+                cs.enforce_constraint(lc!() + included, lc!() + Variable::One - previous, lc!())?;
+            }
+            cs.enforce_constraint(lc!() + Variable::One - included, lc!() + word, lc!())?;
+            mask.push(included);
+        }
+        Ok(Self {
+            words,
+            mask,
+            tape: tape.to_vec(),
+        })
+    }
+
+    /// Number of words on the tape.
+    fn len(&self) -> LinearCombination<Fr> {
+        self.mask.iter().fold(lc!(), |len, &included| len + included)
+    }
+
+    /// Enforces that `value` is the word at `head` whenever the boolean `reading` is
+    /// set, which requires the tape to hold a word there. `head_value` is the index
+    /// the recorded step reads, if it does.
+    fn read(
+        &self,
+        cs: &ConstraintSystemRef<Fr>,
+        reading: LinearCombination<Fr>,
+        head: Variable,
+        head_value: Option<u32>,
+        value: LinearCombination<Fr>,
+    ) -> Result<(), SynthesisError> {
+        let mut hits = lc!();
+        let mut index = lc!();
+        let mut selected = lc!();
+        for (j, (&word, &included)) in self.words.iter().zip(&self.mask).enumerate() {
+            let hit = head_value == Some(j as u32);
+            let hit_var = cs.new_witness_variable(|| Ok(Fr::from(hit)))?;
+            gadgets::enforce_boolean(cs, hit_var)?;
+            cs.enforce_constraint(lc!() + hit_var, lc!() + Variable::One - included, lc!())?;
+            hits = hits + hit_var;
+            index += (Fr::from(j as u64), hit_var);
+
+            let word_value = if hit { Fr::from(self.tape.get(j).copied().unwrap_or(0)) } else { Fr::zero() };
+            selected = selected + gadgets::product(cs, lc!() + hit_var, lc!() + word, word_value)?;
+        }
+
+        // One hit while reading and none otherwise, so `selected` is zero unless reading
+        gadgets::enforce_equal(cs, hits, reading.clone())?;
+        gadgets::enforce_equal_if(cs, reading.clone(), index, lc!() + head)?;
+        gadgets::enforce_equal_if(cs, reading, value, selected)
+    }
+}
+
+/// The public and the secret input tape, and the output tape.
+struct Tapes {
+    public: TapeVars,
+    secret: TapeVars,
+    output: TapeVars,
+}
+
+/// One-hot opcode selectors of a step, in the order of `Opcode::ALL`.
+struct Selectors(Vec<Variable>);
+
+impl Selectors {
+    fn get(&self, opcode: Opcode) -> Variable {
+        self.0[opcode.index()]
+    }
+
+    /// Set when the step runs any of `opcodes`.
+    fn any(&self, opcodes: &[Opcode]) -> LinearCombination<Fr> {
+        opcodes.iter().fold(lc!(), |any, &opcode| any + self.get(opcode))
+    }
+}
+
+/// Instructions that report a carry, see `CARRY_FLAG`.
+const CARRY_OPS: [Opcode; 3] = [Opcode::ADD, Opcode::SUB, Opcode::MUL];
+
+/// Comparisons, which set `EQUAL_FLAG` and `LESS_FLAG`.
+const COMPARISONS: [Opcode; 5] = [Opcode::EQ, Opcode::LT, Opcode::GT, Opcode::SLT, Opcode::SGT];
+
+/// Instructions that may move the pc to their operand.
+const JUMPS: [Opcode; 6] = [Opcode::JMP, Opcode::JZ, Opcode::JNZ, Opcode::JLT, Opcode::JEQ, Opcode::CALL];
+
+/// Values an instruction pops off and pushes onto the stack.
+fn stack_effect(opcode: Opcode) -> (usize, usize) {
+    match opcode {
+        Opcode::PUSH | Opcode::LOAD | Opcode::DUP | Opcode::READ | Opcode::READS => (0, 1),
+        Opcode::POP | Opcode::STORE | Opcode::JZ | Opcode::JNZ | Opcode::WRITE => (1, 0),
+        Opcode::NOT | Opcode::LOADI => (1, 1),
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIVU
+        | Opcode::MODU
+        | Opcode::AND
+        | Opcode::OR
+        | Opcode::XOR
+        | Opcode::SHL
+        | Opcode::SHR
+        | Opcode::EQ
+        | Opcode::LT
+        | Opcode::GT
+        | Opcode::SLT
+        | Opcode::SGT => (2, 1),
+        Opcode::STOREI => (2, 0),
+        Opcode::OVER => (2, 3),
+        Opcode::ROT => (3, 3),
+        Opcode::JMP | Opcode::JLT | Opcode::JEQ | Opcode::HALT | Opcode::SWAP | Opcode::CALL | Opcode::RET => (0, 0),
+    }
+}
+
+fn takes_operand(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::PUSH | Opcode::LOAD | Opcode::STORE | Opcode::DUP | Opcode::SWAP
+    ) || JUMPS.contains(&opcode)
+}
+
+/// What a key pair is bound to: the bounds, public layout and gas costs of an
+/// `ExecutionCircuit`, the size of the constraint system it synthesises and a
+/// SHA-256 digest of its matrices.
+///
+/// The constraint system only depends on the bounds, the public layout and the gas
+/// costs, so proving takes them from the shape without synthesising it again. The
+/// digest also changes with the constraints themselves, so keys and proofs made by
+/// an older version of the circuit are told apart from current ones.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitShape {
+    pub bounds: CircuitBounds,
+    pub layout: PublicInputLayout,
+    pub gas_costs: GasCosts,
+    pub constraints: usize,
+    /// Public inputs, counting the constant one.
+    pub instance_variables: usize,
+    pub witness_variables: usize,
+    pub matrices: Vec<u8>,
+}
+
+impl fmt::Display for CircuitShape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} constraints over {} public and {} witness variables, matrices {}",
+            self.constraints,
+            self.instance_variables,
+            self.witness_variables,
+            hex::encode(&self.matrices)
+        )
+    }
+}
+
+/// Proves that `trace` is an execution of `program`.
+///
+/// `gas_costs` is part of the circuit like the bounds and the layout: keys set up
+/// for one cost table only prove gas use under that table.
+#[derive(Clone)]
+pub struct ExecutionCircuit {
+    pub bounds: CircuitBounds,
+    pub program: Vec<Instruction>,
+    pub trace: Vec<ProvableState>,
+    /// Commitment to `trace` under `trace_blinding`, computed by `new`.
+    pub trace_commitment: Vec<u8>,
+    /// The blinding of the trace commitment, see `ProvableVM::trace_blinding`. It
+    /// stays in the witness.
+    pub trace_blinding: Fr,
+    pub public_layout: PublicInputLayout,
+    pub memory: MemoryModel,
+    pub inputs: InputTapes,
+    pub gas_costs: GasCosts,
+}
+
+impl ExecutionCircuit {
+    /// The circuit for `trace`, committed to under `trace_blinding`.
+    pub fn new(
+        bounds: CircuitBounds,
+        program: &[Instruction],
+        trace: &[ProvableState],
+        trace_blinding: Fr,
+        public_layout: PublicInputLayout,
+        memory: MemoryModel,
+        inputs: InputTapes,
+    ) -> Result<Self, CircuitError> {
+        let mut circuit = Self {
+            bounds,
+            program: program.to_vec(),
+            trace: trace.to_vec(),
+            trace_commitment: Vec::new(),
+            trace_blinding,
+            public_layout,
+            memory,
+            inputs,
+            gas_costs: GasCosts::default(),
+        };
+        circuit.validate()?;
+        let commitment = commitment::trace_commitment(&circuit.trace, &bounds, trace_blinding);
+        circuit.trace_commitment = commitment::commitment_to_bytes(commitment);
+        Ok(circuit)
+    }
+
+    /// Checks that the program, trace, tapes, memory image and public layout fit in
+    /// the bounds and agree with each other.
+    pub fn validate(&self) -> Result<(), CircuitError> {
+        self.check_sizes()?;
+        if self
+            .trace
+            .first()
+            .is_some_and(|state| state.heap != self.memory.image().into_iter().collect())
+        {
+            return Err(CircuitError::ImageMismatch);
+        }
+        // A cell listed twice would put two accesses at the same address and time
+        // into the memory log
+        for addresses in [&self.public_layout.initial_heap, &self.public_layout.final_heap] {
+            let repeated = addresses
+                .iter()
+                .enumerate()
+                .find_map(|(i, address)| addresses[..i].contains(address).then_some(address));
+            if let Some(&address) = repeated {
+                return Err(CircuitError::RepeatedPublicCell { address });
+            }
+        }
+        Ok(())
+    }
+
+    /// The part of `validate` synthesis relies on: everything that would change the
+    /// shape of the circuit fits in the bounds. Whether the witness is consistent is
+    /// left to the constraints.
+    fn check_sizes(&self) -> Result<(), CircuitError> {
+        let bounds = &self.bounds;
+        bounds.check(&self.program, &self.trace)?;
+        for (secret, tape) in [(false, &self.inputs.public), (true, &self.inputs.secret)] {
+            if tape.len() > bounds.max_input {
+                return Err(CircuitError::InputTooLong {
+                    secret,
+                    len: tape.len(),
+                    max: bounds.max_input,
+                });
+            }
+        }
+        let cells = self.memory.image().len();
+        if cells > bounds.max_image {
+            return Err(CircuitError::ImageTooLarge {
+                cells,
+                max: bounds.max_image,
+            });
+        }
+        let written = self.trace.last().map_or(0, |state| state.written as usize);
+        if written > bounds.max_output {
+            return Err(CircuitError::OutputTooLong {
+                len: written,
+                max: bounds.max_output,
+            });
+        }
+        Ok(())
+    }
+
+    /// The circuit for the empty program, which has the same shape as every other
+    /// circuit with these bounds and layout. Keys are set up from it.
+    pub fn blank(bounds: CircuitBounds, public_layout: PublicInputLayout) -> Result<Self, CircuitError> {
+        Self::new(
+            bounds,
+            &[],
+            &[ProvableState::default()],
+            Fr::zero(),
+            public_layout,
+            MemoryModel::default(),
+            InputTapes::default(),
+        )
+    }
+
+    /// The shape of the constraint system, synthesised the way Groth16 setup does.
+    pub fn shape(&self) -> Result<CircuitShape, SynthesisError> {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        cs.set_optimization_goal(OptimizationGoal::Constraints);
+        cs.set_mode(SynthesisMode::Setup);
+        self.clone().generate_constraints(cs.clone())?;
+        cs.finalize();
+        let matrices = cs.to_matrices().ok_or(SynthesisError::MissingCS)?;
+
+        // Every row as its length followed by its (coefficient, variable) entries
+        let mut hasher = Sha256::new();
+        for matrix in [&matrices.a, &matrices.b, &matrices.c] {
+            for row in matrix {
+                hasher.update((row.len() as u64).to_le_bytes());
+                for (coeff, index) in row {
+                    hasher.update(coeff.into_bigint().to_bytes_le());
+                    hasher.update((*index as u64).to_le_bytes());
+                }
+            }
+        }
+        Ok(CircuitShape {
+            bounds: self.bounds,
+            layout: self.public_layout.clone(),
+            gas_costs: self.gas_costs.clone(),
+            constraints: matrices.num_constraints,
+            instance_variables: matrices.num_instance_variables,
+            witness_variables: matrices.num_witness_variables,
+            matrices: hasher.finalize().to_vec(),
+        })
+    }
+
+    /// The public inputs a proof of this circuit is verified against.
+    pub fn public_inputs(&self) -> PublicInputs {
+        PublicInputs::from_trace(
+            &self.public_layout,
+            &self.program,
+            &self.memory,
+            &self.inputs.public,
+            &self.trace,
+            &self.trace_commitment,
+        )
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for ExecutionCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        // Synthesis can only report that the circuit cannot be satisfied; callers that
+        // need the reason run `ExecutionCircuit::validate` first, as `new` does
+        self.check_sizes().map_err(|_| SynthesisError::Unsatisfiable)?;
+        let bounds = self.bounds;
+
+        // Allocate the public inputs in the order laid out by `PublicInputs::to_field_elements`
+        let public_inputs = self.public_inputs();
+        let input_vars = public_inputs
+            .to_field_elements(&bounds)
+            .into_iter()
+            .map(|value| cs.new_input_variable(|| Ok(value)))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Everything public goes into the memory log's permutation challenge
+        let seed = input_vars.clone();
+        let mut inputs = input_vars.into_iter();
+        let trace_commitment_var = next_input(&mut inputs)?;
+        let program_hash_var = next_input(&mut inputs)?;
+
+        // The program is a witness like the trace; only its hash is public
+        let (program, mask) = ProgramVars::alloc(&cs, &bounds, &self.program)?;
+        let program_hash = commitment::program_commitment_gadget(&cs, &program.entries, &mask)?;
+        gadgets::enforce_equal(&cs, gadgets::fp_var_lc(&program_hash), lc!() + program_hash_var)?;
+
+        // The memory model is public: whether loads of cells nothing was stored to
+        // read zero, and the image the heap starts out with
+        let zero_initialized = next_input(&mut inputs)?;
+        gadgets::enforce_boolean(&cs, zero_initialized)?;
+        let image_hash_var = next_input(&mut inputs)?;
+        let (image_hash, mut memory_log) =
+            memory::alloc_image(&cs, &self.memory.image(), bounds.max_image, bounds.max_steps)?;
+        gadgets::enforce_equal(&cs, gadgets::fp_var_lc(&image_hash), lc!() + image_hash_var)?;
+
+        // Both input tapes are witnesses, the public one is bound to its public hash.
+        // The output tape is public word by word.
+        let public = TapeVars::alloc(&cs, &bounds, &self.inputs.public)?;
+        let input_hash = commitment::input_commitment_gadget(&cs, &public.words, &public.mask)?;
+        gadgets::enforce_equal(&cs, gadgets::fp_var_lc(&input_hash), lc!() + next_input(&mut inputs)?)?;
+        let output_len = next_input(&mut inputs)?;
+        let output_words = (0..bounds.max_output).map(|_| next_input(&mut inputs)).collect::<Result<_, _>>()?;
+        let output = TapeVars::new(&cs, output_words, &public_inputs.output)?;
+        gadgets::enforce_equal(&cs, output.len(), lc!() + output_len)?;
+        let tapes = Tapes {
+            public,
+            secret: TapeVars::alloc(&cs, &bounds, &self.inputs.secret)?,
+            output,
+        };
+
+        // Every state of the padded trace becomes a set of witness variables
+        let trace = bounds.pad_trace(&self.trace);
+        let states = trace
+            .iter()
+            .map(|state| StateVars::alloc(&cs, &bounds, state))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Bind the public trace commitment to the witnessed states and blinding
+        let encodings = states
+            .iter()
+            .map(|state| state.encode(&cs))
+            .collect::<Result<Vec<_>, _>>()?;
+        let blinding = cs.new_witness_variable(|| Ok(self.trace_blinding))?;
+        let commitment = commitment::trace_commitment_gadget(&cs, &bounds.header(), blinding, &encodings)?;
+        gadgets::enforce_equal(&cs, gadgets::fp_var_lc(&commitment), lc!() + trace_commitment_var)?;
+
+        // Execution starts from a reset machine. Parts exposed by the public layout are
+        // bound to public inputs, everything else is empty.
+        let first = states.first().ok_or(SynthesisError::Unsatisfiable)?;
+        enforce_constant(&cs, first.pc, Fr::zero())?;
+        enforce_constant(&cs, first.flags, Fr::zero())?;
+        enforce_constant(&cs, first.csp, Fr::zero())?;
+        enforce_constant(&cs, first.public_read, Fr::zero())?;
+        enforce_constant(&cs, first.secret_read, Fr::zero())?;
+        enforce_constant(&cs, first.written, Fr::zero())?;
+        enforce_constant(&cs, first.gas_used, Fr::zero())?;
+        if self.public_layout.initial_stack {
+            gadgets::enforce_equal(&cs, lc!() + first.sp, lc!() + next_input(&mut inputs)?)?;
+            // Public slots are range checked here, every later one derives from words
+            for (&slot, value) in first.stack.iter().zip(bounds.stack_slots(&trace[0])) {
+                gadgets::enforce_equal(&cs, lc!() + slot, lc!() + next_input(&mut inputs)?)?;
+                gadgets::to_bits(&cs, slot, value as u64, WORD_BITS)?;
+            }
+        } else {
+            enforce_constant(&cs, first.sp, Fr::zero())?;
+        }
+
+        // Public initial cells are read back from the image before the first step
+        for (&address, &(_, cell)) in self.public_layout.initial_heap.iter().zip(&public_inputs.initial_heap) {
+            memory_log.push(public_cell(&cs, &mut inputs, Access::initial(address, cell))?);
+        }
+
+        // Each step runs the instruction the program holds at the current pc
+        let mut halted = None;
+        for (i, step) in trace.windows(2).enumerate() {
+            let (halt, access) = enforce_step(&cs, &program, &tapes, &self.gas_costs, &states[i..=i + 1], step, i)?;
+            memory::enforce_initialized(&cs, &access, zero_initialized)?;
+            halted = Some(halt);
+            memory_log.push(access);
+        }
+
+        // The last step must be a HALT, so the execution is complete
+        if let Some(halted) = halted {
+            enforce_constant(&cs, halted, Fr::one())?;
+        }
+
+        // Bind the exposed parts of the final state. The public output is everything the
+        // program wrote, not a prefix of it.
+        let last = states.last().ok_or(SynthesisError::Unsatisfiable)?;
+        gadgets::enforce_equal(&cs, lc!() + last.written, lc!() + output_len)?;
+        if self.public_layout.final_stack_top {
+            // The top is present unless the stack is empty, when its slot is zero
+            let empty = gadgets::is_zero(&cs, last.sp, Fr::from(trace[trace.len() - 1].stack.len() as u64))?;
+            gadgets::enforce_equal(&cs, lc!() + Variable::One - empty, lc!() + next_input(&mut inputs)?)?;
+            gadgets::enforce_equal(&cs, lc!() + last.stack[0], lc!() + next_input(&mut inputs)?)?;
+        }
+        // Public final cells are read back after the last step
+        for (&address, &(_, cell)) in self.public_layout.final_heap.iter().zip(&public_inputs.final_heap) {
+            let access = Access::last(address, cell, bounds.max_steps);
+            memory_log.push(public_cell(&cs, &mut inputs, access)?);
+        }
+        if self.public_layout.final_pc {
+            gadgets::enforce_equal(&cs, lc!() + last.pc, lc!() + next_input(&mut inputs)?)?;
+        }
+        if self.public_layout.final_gas {
+            gadgets::enforce_equal(&cs, lc!() + last.gas_used, lc!() + next_input(&mut inputs)?)?;
+        }
+
+        // Every read of the heap returns what the last write to its address stored
+        memory::enforce_consistency(&cs, &seed, &memory_log)?;
+
+        Ok(())
+    }
+}
+
+/// Constrains step `i` of the machine, the transition between the two `states`
+/// recorded as `transition`. Returns the selector telling whether the step ran a
+/// HALT and the step's memory log entry.
+fn enforce_step(
+    cs: &ConstraintSystemRef<Fr>,
+    program: &ProgramVars,
+    tapes: &Tapes,
+    gas_costs: &GasCosts,
+    states: &[StateVars],
+    transition: &[ProvableState],
+    i: usize,
+) -> Result<(Variable, AccessVars), SynthesisError> {
+    let step = Step::decode(cs, program, &states[0], &states[1], &transition[0], &transition[1])?;
+    enforce_stack(&step)?;
+    enforce_stack_access(&step)?;
+    let bits = OperandBits::decompose(&step)?;
+    let shift = enforce_bitwise(&step, &bits)?;
+    let carry = enforce_arithmetic(&step, shift)?;
+    let (equal, less) = enforce_comparison(&step, &bits)?;
+    enforce_flags(&step, carry, equal, less)?;
+    let access = enforce_memory(&step, i)?;
+    enforce_tapes(&step, tapes)?;
+    enforce_gas(&step, gas_costs)?;
+    enforce_calls(&step)?;
+    enforce_control_flow(&step, program)?;
+    Ok((step.selectors.get(Opcode::HALT), access))
+}
+
+/// Witnesses shared by the constraints of one transition `prev -> next`.
+///
+/// The instruction is fetched from the witnessed program at `prev.pc` and decoded
+/// into one-hot opcode selectors. Every instruction's constraints are gated by its
+/// selector, so the constraint system is the same whatever the program.
+struct Step<'a> {
+    cs: &'a ConstraintSystemRef<Fr>,
+    prev: &'a StateVars,
+    next: &'a StateVars,
+    state: &'a ProvableState,
+    next_state: &'a ProvableState,
+    selectors: Selectors,
+    /// Bit decomposition of the flags before the step.
+    flag_bits: Vec<Variable>,
+    /// Opcode of the recorded step, which the witnesses are assigned for.
+    current: Option<Opcode>,
+    operand: LinearCombination<Fr>,
+    operand_value: u32,
+}
+
+impl<'a> Step<'a> {
+    fn decode(
+        cs: &'a ConstraintSystemRef<Fr>,
+        program: &ProgramVars,
+        prev: &'a StateVars,
+        next: &'a StateVars,
+        state: &'a ProvableState,
+        next_state: &'a ProvableState,
+    ) -> Result<Self, SynthesisError> {
+        // Opcode in the low 8 bits, then the operand flag and the operand
+        let instruction_value = program.native(state.pc);
+        let instruction = program.fetch(cs, prev.pc, state.pc)?;
+        let bits = gadgets::to_bits(cs, instruction, instruction_value, INSTRUCTION_BITS)?;
+        let opcode = pack_lc(bits[..8].iter().map(|&bit| lc!() + bit), 1);
+        let has_operand = bits[8];
+        let operand = pack_lc(bits[9..].iter().map(|&bit| lc!() + bit), 1);
+        let opcode_value = instruction_value & 0xff;
+        let current = Opcode::ALL.iter().copied().find(|&op| op as u64 == opcode_value);
+
+        let selectors = Selectors(
+            Opcode::ALL
+                .iter()
+                .map(|&op| {
+                    let selector = cs.new_witness_variable(|| Ok(Fr::from(current == Some(op))))?;
+                    gadgets::enforce_boolean(cs, selector)?;
+                    Ok(selector)
+                })
+                .collect::<Result<Vec<_>, SynthesisError>>()?,
+        );
+        // Exactly one selector is set and it names the decoded opcode, which rules out
+        // opcodes the VM does not know
+        gadgets::enforce_equal(cs, selectors.any(&Opcode::ALL), lc!() + Variable::One)?;
+        let selected_opcode = Opcode::ALL
+            .iter()
+            .fold(lc!(), |sum, &op| sum + (Fr::from(op as u64), selectors.get(op)));
+        gadgets::enforce_equal(cs, selected_opcode, opcode)?;
+
+        let operand_ops: Vec<Opcode> = Opcode::ALL.iter().copied().filter(|&op| takes_operand(op)).collect();
+        gadgets::enforce_equal_if(cs, selectors.any(&operand_ops), lc!() + has_operand, lc!() + Variable::One)?;
+
+        let flag_bits = gadgets::to_bits(cs, prev.flags, state.flags as u64, FLAG_BITS)?;
+
+        Ok(Self {
+            cs,
+            prev,
+            next,
+            state,
+            next_state,
+            selectors,
+            flag_bits,
+            current,
+            operand,
+            operand_value: (instruction_value >> 9) as u32,
+        })
+    }
+
+    /// Whether the recorded step runs one of `opcodes`.
+    fn runs(&self, opcodes: &[Opcode]) -> bool {
+        self.current.is_some_and(|op| opcodes.contains(&op))
+    }
+
+    /// Set when the step runs `opcode`.
+    fn when(&self, opcode: Opcode) -> LinearCombination<Fr> {
+        lc!() + self.selectors.get(opcode)
+    }
+
+    /// Stack slot `k`, top-first, before the step.
+    fn slot(&self, k: usize) -> LinearCombination<Fr> {
+        self.prev.stack.get(k).map_or(lc!(), |&slot| lc!() + slot)
+    }
+
+    fn slot_value(&self, k: usize) -> u32 {
+        self.state.stack.iter().rev().nth(k).copied().unwrap_or(0)
+    }
+
+    /// Call stack slot `k`, innermost-first, before the step.
+    fn return_address(&self, k: usize) -> LinearCombination<Fr> {
+        self.prev.calls.get(k).map_or(lc!(), |&slot| lc!() + slot)
+    }
+
+    /// Flag bit `flag` before the step.
+    fn flag(&self, flag: u32) -> Variable {
+        self.flag_bits[flag as usize]
+    }
+
+    /// Enforces that running `opcode` leaves `value` on top of the stack.
+    fn enforce_result(&self, opcode: Opcode, value: LinearCombination<Fr>) -> Result<(), SynthesisError> {
+        self.enforce_slot(opcode, 0, value)
+    }
+
+    /// Enforces that running `opcode` leaves `value` in stack slot `k`.
+    fn enforce_slot(&self, opcode: Opcode, k: usize, value: LinearCombination<Fr>) -> Result<(), SynthesisError> {
+        let slot = self.next.stack.get(k).map_or(lc!(), |&slot| lc!() + slot);
+        gadgets::enforce_equal_if(self.cs, self.when(opcode), slot, value)
+    }
+}
+
+/// The stack pointer moves by `pushes - pops` and the untouched slots shift
+/// accordingly; the values pushed are up to each instruction. Whatever ends up on
+/// top of the stack is range checked as a word.
+fn enforce_stack(step: &Step) -> Result<(), SynthesisError> {
+    let (cs, prev, next) = (step.cs, step.prev, step.next);
+
+    // An instruction popping `pops` values needs slot `pops - 1` to be occupied; the
+    // stack pointer alone would let it pop padding zeros off a shallower stack
+    let mut pop_counts: Vec<usize> = Opcode::ALL.iter().map(|&op| stack_effect(op).0).collect();
+    pop_counts.sort_unstable();
+    pop_counts.dedup();
+    for pops in pop_counts.into_iter().filter(|&pops| pops > 0) {
+        let popping: Vec<Opcode> = Opcode::ALL
+            .iter()
+            .copied()
+            .filter(|&op| stack_effect(op).0 == pops)
+            .collect();
+        let deepest = prev.occupied.get(pops - 1).map_or(lc!(), |&mask| lc!() + mask);
+        gadgets::enforce_equal_if(cs, step.selectors.any(&popping), deepest, lc!() + Variable::One)?;
+    }
+
+    let sp_delta = Opcode::ALL.iter().fold(lc!(), |delta, &op| {
+        let (pops, pushes) = stack_effect(op);
+        delta + (Fr::from(pushes as u64) - Fr::from(pops as u64), step.selectors.get(op))
+    });
+    gadgets::enforce_equal(cs, lc!() + next.sp, lc!() + prev.sp + sp_delta)?;
+
+    let mut shapes: Vec<(usize, usize)> = Opcode::ALL.iter().map(|&op| stack_effect(op)).collect();
+    shapes.sort_unstable();
+    shapes.dedup();
+    for (pops, pushes) in shapes {
+        // SWAP rewrites the slot it exchanges the top with, see `enforce_stack_access`
+        let shape_ops: Vec<Opcode> = Opcode::ALL
+            .iter()
+            .copied()
+            .filter(|&op| op != Opcode::SWAP && stack_effect(op) == (pops, pushes))
+            .collect();
+        let in_shape = step.selectors.any(&shape_ops);
+        for k in pushes..next.stack.len() {
+            let source = step.slot(k + pops - pushes);
+            gadgets::enforce_equal_if(cs, in_shape.clone(), lc!() + next.stack[k], source)?;
+        }
+    }
+
+    let next_top = step.next_state.stack.last().copied().unwrap_or(0);
+    gadgets::to_bits(cs, next.stack[0], next_top as u64, WORD_BITS)?;
+    step.enforce_result(Opcode::PUSH, step.operand.clone())?;
+
+    // OVER: a b -> a b a, ROT: a b c -> b c a
+    step.enforce_slot(Opcode::OVER, 0, step.slot(1))?;
+    step.enforce_slot(Opcode::OVER, 1, step.slot(0))?;
+    step.enforce_slot(Opcode::OVER, 2, step.slot(1))?;
+    step.enforce_slot(Opcode::ROT, 0, step.slot(2))?;
+    step.enforce_slot(Opcode::ROT, 1, step.slot(0))?;
+    step.enforce_slot(Opcode::ROT, 2, step.slot(1))
+}
+
+/// DUP n pushes a copy of the value `n` slots below the top and SWAP n exchanges
+/// the two. The depth is selected one-hot among the occupied slots, so neither can
+/// reach below the bottom of the stack and read a padding zero.
+fn enforce_stack_access(step: &Step) -> Result<(), SynthesisError> {
+    let (cs, prev, next) = (step.cs, step.prev, step.next);
+    let deep_ops = [Opcode::DUP, Opcode::SWAP];
+    let accessing = step.runs(&deep_ops);
+    let target = step.operand_value as usize;
+
+    let mut hits = Vec::with_capacity(prev.stack.len());
+    let mut count = lc!();
+    let mut depth = lc!();
+    let mut picked = lc!();
+    for (k, (&slot, &occupied)) in prev.stack.iter().zip(&prev.occupied).enumerate() {
+        let hit_value = accessing && target == k;
+        let hit = cs.new_witness_variable(|| Ok(Fr::from(hit_value)))?;
+        gadgets::enforce_boolean(cs, hit)?;
+        cs.enforce_constraint(lc!() + hit, lc!() + Variable::One - occupied, lc!())?;
+
+        let value = if hit_value { step.slot_value(k) } else { 0 };
+        picked = picked + gadgets::product(cs, lc!() + hit, lc!() + slot, Fr::from(value))?;
+        count = count + hit;
+        depth += (Fr::from(k as u64), hit);
+        hits.push(hit);
+    }
+    gadgets::enforce_equal(cs, count, step.selectors.any(&deep_ops))?;
+    gadgets::enforce_equal_if(cs, step.selectors.any(&deep_ops), depth, step.operand.clone())?;
+    step.enforce_result(Opcode::DUP, picked.clone())?;
+
+    // SWAP n with n >= 1: slot n takes the old top, every other slot stays put
+    step.enforce_result(Opcode::SWAP, picked)?;
+    if let Some(&top_hit) = hits.first() {
+        gadgets::enforce_equal_if(cs, step.when(Opcode::SWAP), lc!() + top_hit, lc!())?;
+    }
+    for (k, &hit) in hits.iter().enumerate().skip(1) {
+        let value = if accessing && target == k {
+            Fr::from(step.slot_value(0)) - Fr::from(step.slot_value(k))
+        } else {
+            Fr::zero()
+        };
+        let exchanged = gadgets::product(cs, lc!() + hit, step.slot(0) - &step.slot(k), value)?;
+        gadgets::enforce_equal_if(cs, step.when(Opcode::SWAP), lc!() + next.stack[k], step.slot(k) + exchanged)?;
+    }
+    Ok(())
+}
+
+/// Bit decompositions of the two top words, shared by the bitwise, shift and
+/// signed comparison instructions.
+struct OperandBits {
+    top: Vec<Variable>,
+    second: Vec<Variable>,
+}
+
+impl OperandBits {
+    fn decompose(step: &Step) -> Result<Self, SynthesisError> {
+        let cs = step.cs;
+        Ok(Self {
+            top: gadgets::to_bits(cs, cs.new_lc(step.slot(0))?, step.slot_value(0) as u64, WORD_BITS)?,
+            second: gadgets::to_bits(cs, cs.new_lc(step.slot(1))?, step.slot_value(1) as u64, WORD_BITS)?,
+        })
+    }
+
+    fn top_sign(&self) -> Variable {
+        self.top[WORD_BITS - 1]
+    }
+
+    fn second_sign(&self) -> Variable {
+        self.second[WORD_BITS - 1]
+    }
+}
+
+/// AND, OR, XOR and NOT on the bit decompositions of the two top words. Returns
+/// `2^(a mod 32)` for the top word `a`, the factor SHL and SHR shift by.
+fn enforce_bitwise(step: &Step, bits: &OperandBits) -> Result<Variable, SynthesisError> {
+    let cs = step.cs;
+    let (a, b) = (step.slot_value(0), step.slot_value(1));
+    let (a_bits, b_bits) = (&bits.top, &bits.second);
+
+    let mut and = lc!();
+    for (i, (&a_bit, &b_bit)) in a_bits.iter().zip(b_bits).enumerate() {
+        let bit = gadgets::product(cs, lc!() + a_bit, lc!() + b_bit, Fr::from(((a & b) >> i) & 1))?;
+        and += (Fr::from(1u64 << i), bit);
+    }
+
+    // a | b = a + b - (a & b) and a ^ b = a + b - 2 (a & b)
+    let sum = step.slot(0) + step.slot(1);
+    step.enforce_result(Opcode::AND, and.clone())?;
+    step.enforce_result(Opcode::OR, sum.clone() - &and)?;
+    step.enforce_result(Opcode::XOR, sum - (Fr::from(2u64), &and))?;
+    step.enforce_result(Opcode::NOT, lc!() + (Fr::from(u32::MAX), Variable::One) - &step.slot(0))?;
+
+    // 2^(a mod 32) = prod over the low five bits of (1 + bit_i (2^(2^i) - 1))
+    let mut power = lc!() + Variable::One;
+    let mut power_value = 1u64;
+    for (i, &bit) in a_bits.iter().take(SHIFT_BITS).enumerate() {
+        let factor = (1u64 << (1 << i)) - 1;
+        let bit_set = (a >> i) & 1 == 1;
+        let increment = if bit_set { power_value * factor } else { 0 };
+        let raised = gadgets::product(cs, power.clone(), lc!() + (Fr::from(factor), bit), Fr::from(increment))?;
+        power = power + raised;
+        power_value += increment;
+    }
+    cs.new_lc(power)
+}
+
+/// ADD, SUB, MUL, DIVU and MODU on the two top words, with SHL and SHR as a
+/// multiplication and a division by `shift`. Returns the carry of ADD, SUB and
+/// MUL, see `CARRY_FLAG`.
+fn enforce_arithmetic(step: &Step, shift: Variable) -> Result<Variable, SynthesisError> {
+    let cs = step.cs;
+    let (a, b) = (step.slot_value(0), step.slot_value(1));
+    let (top, second) = (step.slot(0), step.slot(1));
+    let wrap = Fr::from(1u64 << WORD_BITS);
+
+    // Words wrap around modulo 2^32 and the wrap shows in the carry. With the
+    // operands and the result range checked as words, the carry is determined.
+    let carry_value = step.runs(&CARRY_OPS) && (step.next_state.flags >> CARRY_FLAG) & 1 == 1;
+    let carry = cs.new_witness_variable(|| Ok(Fr::from(carry_value)))?;
+    gadgets::enforce_boolean(cs, carry)?;
+
+    step.enforce_result(Opcode::ADD, second.clone() + &top - (wrap, carry))?;
+    step.enforce_result(Opcode::SUB, second.clone() - &top + (wrap, carry))?;
+
+    // Multiplications split `b * scale` into the result and a high word, divisions
+    // witness `b = scale * quotient + remainder` with `remainder < scale`. The scale
+    // is `a`, or the power of two for shifts, and one auxiliary word holds the high
+    // word or the quotient.
+    let multiplications = [Opcode::MUL, Opcode::SHL];
+    let divisions = [Opcode::DIVU, Opcode::MODU, Opcode::SHR];
+    let scale_value = if step.runs(&[Opcode::SHL, Opcode::SHR]) {
+        1u64 << (a % WORD_BITS as u32)
+    } else {
+        a as u64
+    };
+    let (aux_value, remainder_value) = if step.runs(&multiplications) {
+        ((b as u64 * scale_value) >> WORD_BITS, 0)
+    } else if step.runs(&divisions) && scale_value != 0 {
+        (b as u64 / scale_value, b as u64 % scale_value)
+    } else {
+        (0, 0)
+    };
+
+    let scale = cs.new_witness_variable(|| Ok(Fr::from(scale_value)))?;
+    gadgets::enforce_equal_if(
+        cs,
+        step.selectors.any(&[Opcode::MUL, Opcode::DIVU, Opcode::MODU]),
+        lc!() + scale,
+        top,
+    )?;
+    gadgets::enforce_equal_if(cs, step.selectors.any(&[Opcode::SHL, Opcode::SHR]), lc!() + scale, lc!() + shift)?;
+
+    let aux = cs.new_witness_variable(|| Ok(Fr::from(aux_value)))?;
+    gadgets::to_bits(cs, aux, aux_value, WORD_BITS)?;
+    let remainder = cs.new_witness_variable(|| Ok(Fr::from(remainder_value)))?;
+    gadgets::to_bits(cs, remainder, remainder_value, WORD_BITS)?;
+
+    let product = gadgets::product(cs, second.clone(), lc!() + scale, Fr::from(b as u64 * scale_value))?;
+    gadgets::enforce_equal_if(
+        cs,
+        step.selectors.any(&multiplications),
+        lc!() + step.next.stack[0] + (wrap, aux),
+        lc!() + product,
+    )?;
+    // MUL carries exactly when the high word is not zero
+    let aux_is_zero = gadgets::is_zero(cs, aux, Fr::from(aux_value))?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::MUL), lc!() + carry, lc!() + Variable::One - aux_is_zero)?;
+
+    let quotient_product = gadgets::product(cs, lc!() + scale, lc!() + aux, Fr::from(scale_value * aux_value))?;
+    let division = step.selectors.any(&divisions);
+    gadgets::enforce_equal_if(cs, division.clone(), second, lc!() + quotient_product + remainder)?;
+    step.enforce_result(Opcode::DIVU, lc!() + aux)?;
+    step.enforce_result(Opcode::SHR, lc!() + aux)?;
+    step.enforce_result(Opcode::MODU, lc!() + remainder)?;
+
+    // scale - 1 - remainder is a word, which also rules out division by zero
+    let slack_value = scale_value.wrapping_sub(1).wrapping_sub(remainder_value);
+    let slack_value = if step.runs(&divisions) { slack_value } else { 0 };
+    let slack = cs.new_witness_variable(|| Ok(Fr::from(slack_value)))?;
+    gadgets::to_bits(cs, slack, slack_value, WORD_BITS)?;
+    gadgets::enforce_equal_if(cs, division, lc!() + slack, lc!() + scale - Variable::One - remainder)?;
+
+    Ok(carry)
+}
+
+/// EQ, LT, GT and their signed variants SLT and SGT push `b op a` as a boolean.
+/// Returns the equal and less flags they set.
+///
+/// The less flag comes out of `b - a + 2^32 less`, which must be a word. Signed
+/// comparisons flip both sign bits first, which maps two's complement order onto
+/// unsigned order and amounts to subtracting `2^32 (b_31 - a_31)`.
+fn enforce_comparison(step: &Step, bits: &OperandBits) -> Result<(Variable, Variable), SynthesisError> {
+    let cs = step.cs;
+    let (a, b) = (step.slot_value(0), step.slot_value(1));
+    let signed_ops = [Opcode::SLT, Opcode::SGT];
+    let signed = step.runs(&signed_ops);
+    let sign = 1u32 << (WORD_BITS - 1);
+    let (a_key, b_key) = if signed { (a ^ sign, b ^ sign) } else { (a, b) };
+    let comparing = step.runs(&COMPARISONS);
+
+    let wrap = Fr::from(1u64 << WORD_BITS);
+    let sign_difference_value = if signed {
+        Fr::from(b >> (WORD_BITS - 1)) - Fr::from(a >> (WORD_BITS - 1))
+    } else {
+        Fr::zero()
+    };
+    let sign_difference = gadgets::product(
+        cs,
+        step.selectors.any(&signed_ops),
+        lc!() + bits.second_sign() - bits.top_sign(),
+        sign_difference_value,
+    )?;
+
+    let difference_value = if comparing { b_key.wrapping_sub(a_key) as u64 } else { 0 };
+    let difference = cs.new_witness_variable(|| Ok(Fr::from(difference_value)))?;
+    gadgets::to_bits(cs, difference, difference_value, WORD_BITS)?;
+    let less = cs.new_witness_variable(|| Ok(Fr::from(comparing && b_key < a_key)))?;
+    gadgets::enforce_boolean(cs, less)?;
+    gadgets::enforce_equal_if(
+        cs,
+        step.selectors.any(&COMPARISONS),
+        lc!() + difference,
+        step.slot(1) - &step.slot(0) - (wrap, sign_difference) + (wrap, less),
+    )?;
+    let equal = gadgets::is_zero(cs, difference, Fr::from(difference_value))?;
+
+    step.enforce_result(Opcode::EQ, lc!() + equal)?;
+    step.enforce_result(Opcode::LT, lc!() + less)?;
+    step.enforce_result(Opcode::SLT, lc!() + less)?;
+    step.enforce_result(Opcode::GT, lc!() + Variable::One - less - equal)?;
+    step.enforce_result(Opcode::SGT, lc!() + Variable::One - less - equal)?;
+
+    Ok((equal, less))
+}
+
+/// Instructions in `CARRY_OPS` replace the carry flag with `carry`, comparisons
+/// replace the equal and less flags; every other instruction keeps the flags.
+fn enforce_flags(step: &Step, carry: Variable, equal: Variable, less: Variable) -> Result<(), SynthesisError> {
+    let (cs, prev, next) = (step.cs, step.prev, step.next);
+    let weight = |flag: u32| Fr::from(1u64 << flag);
+
+    let sets_carry = step.selectors.any(&CARRY_OPS);
+    gadgets::enforce_equal_if(
+        cs,
+        sets_carry.clone(),
+        lc!() + next.flags,
+        lc!() + prev.flags - (weight(CARRY_FLAG), step.flag(CARRY_FLAG)) + (weight(CARRY_FLAG), carry),
+    )?;
+
+    let compares = step.selectors.any(&COMPARISONS);
+    gadgets::enforce_equal_if(
+        cs,
+        compares.clone(),
+        lc!() + next.flags,
+        lc!() + prev.flags
+            - (weight(EQUAL_FLAG), step.flag(EQUAL_FLAG))
+            - (weight(LESS_FLAG), step.flag(LESS_FLAG))
+            + (weight(EQUAL_FLAG), equal)
+            + (weight(LESS_FLAG), less),
+    )?;
+
+    gadgets::enforce_equal_if(
+        cs,
+        lc!() + Variable::One - sets_carry - compares,
+        lc!() + next.flags,
+        lc!() + prev.flags,
+    )
+}
+
+/// LOAD and STORE access the heap cell their operand names, LOADI and STOREI one
+/// whose address they pop off the stack; STOREI stores the value under the address.
+/// Returns the step's entry in the memory log: the cell it read or wrote, or a write
+/// to `NULL_ADDRESS` if it does not touch the heap. Whether reads are consistent
+/// with writes is up to `memory::enforce_consistency`.
+fn enforce_memory(step: &Step, i: usize) -> Result<AccessVars, SynthesisError> {
+    let (cs, next) = (step.cs, step.next);
+    let direct_ops = [Opcode::LOAD, Opcode::STORE];
+    let indirect_ops = [Opcode::LOADI, Opcode::STOREI];
+    let load_ops = [Opcode::LOAD, Opcode::LOADI];
+    let store_ops = [Opcode::STORE, Opcode::STOREI];
+    let memory_ops = [Opcode::LOAD, Opcode::STORE, Opcode::LOADI, Opcode::STOREI];
+    let others: Vec<Opcode> = Opcode::ALL
+        .iter()
+        .copied()
+        .filter(|op| !memory_ops.contains(op))
+        .collect();
+    let access = Access::step(i, step.current, step.operand_value, step.state, step.next_state);
+
+    let address = cs.new_witness_variable(|| Ok(Fr::from(access.address)))?;
+    gadgets::enforce_equal_if(cs, step.selectors.any(&direct_ops), lc!() + address, step.operand.clone())?;
+    gadgets::enforce_equal_if(cs, step.selectors.any(&indirect_ops), lc!() + address, step.slot(0))?;
+    gadgets::enforce_equal_if(
+        cs,
+        step.selectors.any(&others),
+        lc!() + address,
+        lc!() + (Fr::from(NULL_ADDRESS), Variable::One),
+    )?;
+
+    // Loads push the value they read; STORE writes the top, STOREI the value under
+    // the address
+    let value = cs.new_witness_variable(|| Ok(Fr::from(access.value)))?;
+    gadgets::enforce_equal_if(cs, step.selectors.any(&load_ops), lc!() + value, lc!() + next.stack[0])?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::STORE), lc!() + value, step.slot(0))?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::STOREI), lc!() + value, step.slot(1))?;
+    gadgets::enforce_equal_if(cs, step.selectors.any(&others), lc!() + value, lc!())?;
+
+    // Stores write a present cell. A load may find the cell absent, which only a
+    // zero-initialised memory allows, see `memory::enforce_initialized`.
+    let found = cs.new_witness_variable(|| Ok(Fr::from(access.present && !access.write)))?;
+    gadgets::enforce_boolean(cs, found)?;
+    gadgets::enforce_equal_if(
+        cs,
+        lc!() + Variable::One - &step.selectors.any(&load_ops),
+        lc!() + found,
+        lc!(),
+    )?;
+
+    // Every field is a word or a bit already: the address is the operand, a stack
+    // slot or `NULL_ADDRESS`, and the value a stack slot
+    Ok(AccessVars {
+        address: lc!() + address,
+        time: lc!() + (Fr::from(memory::step_time(i)), Variable::One),
+        value: lc!() + value,
+        present: step.selectors.any(&store_ops) + found,
+        write: lc!() + Variable::One - &step.selectors.any(&load_ops),
+        access,
+    })
+}
+
+/// READ pushes the next word of the public input tape and READS the next word of the
+/// secret one; WRITE pops the top into the next word of the output tape. Each moves
+/// its tape's head on by one, and running past the end of a tape is unsatisfiable.
+fn enforce_tapes(step: &Step, tapes: &Tapes) -> Result<(), SynthesisError> {
+    let (cs, prev, next, state) = (step.cs, step.prev, step.next, step.state);
+    let accesses = [
+        (Opcode::READ, &tapes.public, prev.public_read, next.public_read, state.public_read, next.stack[0]),
+        (Opcode::READS, &tapes.secret, prev.secret_read, next.secret_read, state.secret_read, next.stack[0]),
+        (Opcode::WRITE, &tapes.output, prev.written, next.written, state.written, prev.stack[0]),
+    ];
+    for (opcode, tape, head, next_head, head_value, word) in accesses {
+        let head_value = step.runs(&[opcode]).then_some(head_value);
+        tape.read(cs, step.when(opcode), head, head_value, lc!() + word)?;
+        gadgets::enforce_equal(cs, lc!() + next_head, lc!() + head + &step.when(opcode))?;
+    }
+    Ok(())
+}
+
+/// Every step charges the gas its opcode costs.
+fn enforce_gas(step: &Step, gas_costs: &GasCosts) -> Result<(), SynthesisError> {
+    let cost = Opcode::ALL.iter().fold(lc!(), |cost, &opcode| {
+        cost + (Fr::from(gas_costs.cost(opcode)), step.selectors.get(opcode))
+    });
+    gadgets::enforce_equal(step.cs, lc!() + step.next.gas_used, lc!() + step.prev.gas_used + &cost)
+}
+
+/// CALL pushes the address of the instruction after it onto the call stack and RET
+/// pops it again, so RET returns to exactly the pc its CALL pushed. Every other
+/// instruction leaves the call stack alone.
+fn enforce_calls(step: &Step) -> Result<(), SynthesisError> {
+    let (cs, prev, next) = (step.cs, step.prev, step.next);
+    let others: Vec<Opcode> = Opcode::ALL
+        .iter()
+        .copied()
+        .filter(|op| !matches!(op, Opcode::CALL | Opcode::RET))
+        .collect();
+    let keeps = step.selectors.any(&others);
+
+    // The mask under `csp` keeps the depth within `0..=max_call_depth`, so RET on an
+    // empty call stack and CALL on a full one are unsatisfiable
+    gadgets::enforce_equal(
+        cs,
+        lc!() + next.csp,
+        lc!() + prev.csp + step.selectors.get(Opcode::CALL) - step.selectors.get(Opcode::RET),
+    )?;
+
+    for (k, &slot) in next.calls.iter().enumerate() {
+        let pushed = if k == 0 {
+            lc!() + prev.pc + Variable::One
+        } else {
+            step.return_address(k - 1)
+        };
+        gadgets::enforce_equal_if(cs, step.when(Opcode::CALL), lc!() + slot, pushed)?;
+        gadgets::enforce_equal_if(cs, step.when(Opcode::RET), lc!() + slot, step.return_address(k + 1))?;
+        gadgets::enforce_equal_if(cs, keeps.clone(), lc!() + slot, step.return_address(k))?;
+    }
+    Ok(())
+}
+
+/// Moves the pc: one forward for straight-line instructions, to the target for
+/// JMP, CALL and a taken conditional jump, back to the return address for RET and
+/// nowhere for HALT.
+fn enforce_control_flow(step: &Step, program: &ProgramVars) -> Result<(), SynthesisError> {
+    let (cs, prev, next) = (step.cs, step.prev, step.next);
+    let pc_next = lc!() + prev.pc + Variable::One;
+
+    let sequential: Vec<Opcode> = Opcode::ALL
+        .iter()
+        .copied()
+        .filter(|op| !matches!(op, Opcode::HALT | Opcode::RET) && !JUMPS.contains(op))
+        .collect();
+    gadgets::enforce_equal_if(cs, step.selectors.any(&sequential), lc!() + next.pc, pc_next.clone())?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::HALT), lc!() + next.pc, lc!() + prev.pc)?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::JMP), lc!() + next.pc, step.operand.clone())?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::CALL), lc!() + next.pc, step.operand.clone())?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::RET), lc!() + next.pc, step.return_address(0))?;
+
+    // JZ: next_pc = pc + 1 + is_zero * (target - pc - 1), so the branch taken must
+    // agree with whether the popped value is zero. JNZ takes the other branch:
+    // next_pc = target - is_zero * (target - pc - 1)
+    let offset = Fr::from(step.operand_value) - Fr::from(step.state.pc) - Fr::one();
+    let top_value = step.slot_value(0);
+    let is_zero_var = gadgets::is_zero(cs, prev.stack[0], Fr::from(top_value))?;
+    let branch = gadgets::product(
+        cs,
+        lc!() + is_zero_var,
+        step.operand.clone() - prev.pc - Variable::One,
+        if top_value == 0 { offset } else { Fr::zero() },
+    )?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::JZ), lc!() + next.pc, pc_next.clone() + branch)?;
+    gadgets::enforce_equal_if(cs, step.when(Opcode::JNZ), lc!() + next.pc, step.operand.clone() - branch)?;
+
+    // JLT: next_pc = pc + 1 + flag * (target - pc - 1), reading the less flag,
+    // and JEQ likewise on the equal flag
+    for (opcode, flag) in [(Opcode::JLT, LESS_FLAG), (Opcode::JEQ, EQUAL_FLAG)] {
+        let flag_set = step.state.flags >> flag & 1 == 1;
+        let flag_branch = gadgets::product(
+            cs,
+            lc!() + step.flag(flag),
+            step.operand.clone() - prev.pc - Variable::One,
+            if flag_set { offset } else { Fr::zero() },
+        )?;
+        gadgets::enforce_equal_if(cs, step.when(opcode), lc!() + next.pc, pc_next.clone() + flag_branch)?;
+    }
+
+    // Jump targets lie inside the program: len - 1 - target is a word
+    let jumps = JUMPS;
+    let slack_value = if step.runs(&jumps) {
+        (program.program_len as u64).wrapping_sub(1).wrapping_sub(step.operand_value as u64)
+    } else {
+        0
+    };
+    let slack = cs.new_witness_variable(|| Ok(Fr::from(slack_value)))?;
+    gadgets::to_bits(cs, slack, slack_value, WORD_BITS)?;
+    gadgets::enforce_equal_if(
+        cs,
+        step.selectors.any(&jumps),
+        lc!() + slack,
+        program.len.clone() - Variable::One - step.operand.clone(),
+    )
+}
+
+/// The memory log entry of a public heap cell, whose presence and value are the next
+/// two public inputs. Both are range checked like every other entry.
+fn public_cell(
+    cs: &ConstraintSystemRef<Fr>,
+    inputs: &mut impl Iterator<Item = Variable>,
+    access: Access,
+) -> Result<AccessVars, SynthesisError> {
+    let present = next_input(inputs)?;
+    let value = next_input(inputs)?;
+    gadgets::enforce_boolean(cs, present)?;
+    gadgets::to_bits(cs, value, access.value as u64, WORD_BITS)?;
+    Ok(AccessVars {
+        present: lc!() + present,
+        value: lc!() + value,
+        ..AccessVars::constant(access)
+    })
+}
+
+/// The next public input. There is one for every entry of the layout, so running
+/// out means the circuit and `PublicInputs::to_field_elements` disagree.
+fn next_input(inputs: &mut impl Iterator<Item = Variable>) -> Result<Variable, SynthesisError> {
+    inputs.next().ok_or(SynthesisError::AssignmentMissing)
+}
+
+fn enforce_constant(cs: &ConstraintSystemRef<Fr>, var: Variable, value: Fr) -> Result<(), SynthesisError> {
+    gadgets::enforce_equal(cs, lc!() + var, lc!() + (value, Variable::One))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{instruction, temp_path};
+    use crate::vm::{ProvableVM, VmError};
+    use std::collections::{BTreeMap, HashMap};
+
+    /// Small bounds keep the tests fast; the factorial needs 41 steps.
+    const BOUNDS: CircuitBounds = CircuitBounds {
+        max_steps: 48,
+        max_stack: 4,
+        max_program: 24,
+        max_call_depth: 2,
+        max_image: 2,
+        max_input: 2,
+        max_output: 2,
+    };
+
+    fn circuit_for(program: Vec<Instruction>) -> ExecutionCircuit {
+        circuit_with(program, MemoryModel::Strict, InputTapes::default())
+    }
+
+    fn circuit_with(program: Vec<Instruction>, memory: MemoryModel, inputs: InputTapes) -> ExecutionCircuit {
+        let trace_file = temp_path("provable-vm-circuit-test.trace");
+
+        let mut vm = ProvableVM::with_memory(memory.clone());
+        vm.run_program(&program, &inputs, &trace_file).expect("Failed to execute program");
+
+        ExecutionCircuit::new(
+            BOUNDS,
+            &program,
+            &vm.trace,
+            vm.trace_blinding,
+            PublicInputLayout::default(),
+            memory,
+            inputs,
+        )
+        .unwrap()
+    }
+
+    /// Recomputes the trace commitment after the trace was tampered with, so the
+    /// forgery has to get past the transition constraints.
+    fn recommit(mut circuit: ExecutionCircuit) -> ExecutionCircuit {
+        let commitment = commitment::trace_commitment(&circuit.trace, &circuit.bounds, circuit.trace_blinding);
+        circuit.trace_commitment = commitment::commitment_to_bytes(commitment);
+        circuit
+    }
+
+    fn is_satisfied(circuit: ExecutionCircuit) -> bool {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.is_satisfied().unwrap()
+    }
+
+    fn num_constraints(circuit: ExecutionCircuit) -> usize {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.num_constraints()
+    }
+
+    fn arithmetic_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(10)),
+            instruction(Opcode::PUSH, Some(20)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::SUB, None),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    fn countdown_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(3)),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::JZ, Some(9)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::SUB, None),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::JMP, Some(2)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn honest_traces_are_satisfied() {
+        assert!(is_satisfied(circuit_for(arithmetic_program())));
+        assert!(is_satisfied(circuit_for(countdown_program())));
+        assert!(is_satisfied(ExecutionCircuit::blank(BOUNDS, PublicInputLayout::default()).unwrap()));
+    }
+
+    #[test]
+    fn program_running_off_the_end_is_satisfied() {
+        let program = vec![instruction(Opcode::PUSH, Some(1)), instruction(Opcode::POP, None)];
+        assert!(is_satisfied(circuit_for(program)));
+    }
+
+    #[test]
+    fn circuit_shape_does_not_depend_on_the_program() {
+        let blank = num_constraints(ExecutionCircuit::blank(BOUNDS, PublicInputLayout::default()).unwrap());
+        assert_eq!(num_constraints(circuit_for(arithmetic_program())), blank);
+        assert_eq!(num_constraints(circuit_for(countdown_program())), blank);
+    }
+
+    #[test]
+    fn executions_outside_the_bounds_are_refused() {
+        let mut vm = ProvableVM::new();
+        let program = vec![instruction(Opcode::PUSH, Some(1)); 5];
+        vm.run_program(&program, &InputTapes::default(), "/dev/null").unwrap();
+        let bounds = CircuitBounds { max_stack: 4, ..BOUNDS };
+        assert!(bounds.check(&program, &vm.trace).is_err());
+
+        let bounds = CircuitBounds { max_steps: 4, max_stack: 8, ..BOUNDS };
+        assert!(bounds.check(&program, &vm.trace).is_err());
+    }
+
+    /// Honest trace of `program` up to `at`, continued with the trace of `forged`.
+    fn splice(program: Vec<Instruction>, forged: Vec<Instruction>, at: usize) -> ExecutionCircuit {
+        let mut circuit = circuit_for(program);
+        let forged = circuit_for(forged);
+        circuit.trace.truncate(at);
+        circuit.trace.extend_from_slice(&forged.trace[at..]);
+        recommit(circuit)
+    }
+
+    #[test]
+    fn forged_arithmetic_result_is_rejected() {
+        // The state after ADD claims 10 + 20 = 31
+        let mut forged = arithmetic_program();
+        forged[1] = instruction(Opcode::PUSH, Some(21));
+        assert!(!is_satisfied(splice(arithmetic_program(), forged, 3)));
+    }
+
+    fn wrapping_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::SUB, None),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn wrapping_arithmetic_is_satisfied() {
+        let circuit = circuit_for(wrapping_program());
+        // 1 - 2 borrows, (2^32 - 1) + 1 carries, 0 + 5 clears the carry again
+        let flags: Vec<u8> = circuit.trace.iter().map(|state| state.flags).collect();
+        assert_eq!(flags, vec![0, 0, 0, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(circuit.trace[3].stack, vec![u32::MAX]);
+        assert_eq!(circuit.trace[5].stack, vec![0]);
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn forged_carry_is_rejected() {
+        // The borrow of 1 - 2 is dropped, passing the wrapped result off as exact
+        let mut circuit = circuit_for(wrapping_program());
+        circuit.trace[3].flags = 0;
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // A carry claimed by an ADD that did not overflow
+        let mut circuit = circuit_for(arithmetic_program());
+        for state in &mut circuit.trace[3..] {
+            state.flags = 1;
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    /// 3! with heap cell 0 holding n and cell 1 the running product.
+    fn factorial_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(3)),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::STORE, Some(1)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::JZ, Some(15)),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::MUL, None),
+            instruction(Opcode::STORE, Some(1)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::SUB, None),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::JMP, Some(4)),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    /// gcd(48, 18) by Euclid's algorithm with heap cells 0 and 1 holding a and b.
+    fn gcd_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(48)),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::PUSH, Some(18)),
+            instruction(Opcode::STORE, Some(1)),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::JZ, Some(13)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::MODU, None),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::STORE, Some(1)),
+            instruction(Opcode::JMP, Some(4)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn factorial_and_gcd_are_satisfied() {
+        let factorial = circuit_for(factorial_program());
+        assert_eq!(factorial.trace.last().unwrap().stack, vec![6]);
+        assert!(is_satisfied(factorial));
+
+        let gcd = circuit_for(gcd_program());
+        assert_eq!(gcd.trace.last().unwrap().stack, vec![6]);
+        assert!(is_satisfied(gcd));
+    }
+
+    #[test]
+    fn wrapping_multiplication_is_satisfied() {
+        let circuit = circuit_for(vec![
+            instruction(Opcode::PUSH, Some(0x1_0000)),
+            instruction(Opcode::PUSH, Some(0x1_0001)),
+            instruction(Opcode::MUL, None),
+            instruction(Opcode::HALT, None),
+        ]);
+        assert_eq!(circuit.trace[3].stack, vec![0x1_0000]);
+        assert_eq!(circuit.trace[3].flags, 1);
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn forged_division_is_rejected() {
+        let program = vec![
+            instruction(Opcode::PUSH, Some(17)),
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::DIVU, None),
+            instruction(Opcode::HALT, None),
+        ];
+        assert!(is_satisfied(circuit_for(program.clone())));
+
+        // A quotient of 2 needs a remainder of 7, which is not below the divisor
+        let mut circuit = circuit_for(program.clone());
+        circuit.trace[3].stack = vec![2];
+        circuit.trace[4].stack = vec![2];
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // Likewise for MODU claiming 17 mod 5 = 7
+        let mut modulo = program;
+        modulo[2] = instruction(Opcode::MODU, None);
+        let mut circuit = circuit_for(modulo);
+        circuit.trace[3].stack = vec![7];
+        circuit.trace[4].stack = vec![7];
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    fn bitwise_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(0b1100)),
+            instruction(Opcode::PUSH, Some(0b1010)),
+            instruction(Opcode::AND, None),
+            instruction(Opcode::PUSH, Some(0b0110)),
+            instruction(Opcode::OR, None),
+            instruction(Opcode::PUSH, Some(0b1111)),
+            instruction(Opcode::XOR, None),
+            instruction(Opcode::NOT, None),
+            instruction(Opcode::PUSH, Some(36)),
+            instruction(Opcode::SHL, None),
+            instruction(Opcode::PUSH, Some(31)),
+            instruction(Opcode::SHR, None),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn bitwise_and_shift_instructions_are_satisfied() {
+        let circuit = circuit_for(bitwise_program());
+        let tops: Vec<u32> = circuit.trace.iter().filter_map(|state| state.stack.last().copied()).collect();
+        // 1100 & 1010 = 1000, | 0110 = 1110, ^ 1111 = 0001, !1 = 0xfffffffe,
+        // << (36 mod 32) = 0xffffffe0, >> 31 = 1
+        assert_eq!(
+            tops,
+            vec![0b1100, 0b1010, 0b1000, 0b0110, 0b1110, 0b1111, 0b0001, 0xffff_fffe, 36, 0xffff_ffe0, 31, 1, 1]
+        );
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn forged_bitwise_results_are_rejected() {
+        let cases = [
+            (Opcode::AND, 0b1110),
+            (Opcode::OR, 0b1010),
+            (Opcode::XOR, 0b1110),
+            (Opcode::SHL, 0b1100 << 2),
+            (Opcode::SHR, 1),
+        ];
+        for (opcode, forged) in cases {
+            // 1100 op 1010: the result is in every state from the one after `opcode` on
+            let program = vec![
+                instruction(Opcode::PUSH, Some(0b1100)),
+                instruction(Opcode::PUSH, Some(0b1010)),
+                instruction(opcode, None),
+                instruction(Opcode::HALT, None),
+            ];
+            assert!(is_satisfied(circuit_for(program.clone())));
+
+            let mut circuit = circuit_for(program);
+            for state in &mut circuit.trace[3..] {
+                state.stack = vec![forged];
+            }
+            assert!(!is_satisfied(recommit(circuit)), "forged {:?} result accepted", opcode);
+        }
+
+        let mut circuit = circuit_for(vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::NOT, None),
+            instruction(Opcode::HALT, None),
+        ]);
+        for state in &mut circuit.trace[2..] {
+            state.stack = vec![0];
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    #[test]
+    fn comparisons_are_satisfied() {
+        // 5 < 7 either way; 5 < 2^32 - 1 unsigned but 5 > -1 signed
+        let less = 1 << LESS_FLAG;
+        let cases = [
+            (Opcode::EQ, [(0, less), (0, less)]),
+            (Opcode::LT, [(1, less), (1, less)]),
+            (Opcode::GT, [(0, less), (0, less)]),
+            (Opcode::SLT, [(1, less), (0, 0)]),
+            (Opcode::SGT, [(0, less), (1, 0)]),
+        ];
+        for (opcode, expected) in cases {
+            let mut program = Vec::new();
+            for top in [7, u32::MAX] {
+                program.push(instruction(Opcode::PUSH, Some(5)));
+                program.push(instruction(Opcode::PUSH, Some(top)));
+                program.push(instruction(opcode, None));
+                program.push(instruction(Opcode::POP, None));
+            }
+            program.push(instruction(Opcode::HALT, None));
+
+            let circuit = circuit_for(program);
+            let results: Vec<(u32, u8)> = [3, 7]
+                .iter()
+                .map(|&i| (circuit.trace[i].stack[0], circuit.trace[i].flags))
+                .collect();
+            assert_eq!(results, expected, "{:?}", opcode);
+            assert!(is_satisfied(circuit), "{:?} not satisfied", opcode);
+        }
+
+        let circuit = circuit_for(vec![
+            instruction(Opcode::PUSH, Some(9)),
+            instruction(Opcode::PUSH, Some(9)),
+            instruction(Opcode::EQ, None),
+            instruction(Opcode::HALT, None),
+        ]);
+        assert_eq!(circuit.trace[3].stack, vec![1]);
+        assert_eq!(circuit.trace[3].flags, 1 << EQUAL_FLAG);
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn forged_comparisons_are_rejected() {
+        for opcode in [Opcode::LT, Opcode::SLT, Opcode::GT, Opcode::EQ] {
+            let program = vec![
+                instruction(Opcode::PUSH, Some(5)),
+                instruction(Opcode::PUSH, Some(u32::MAX)),
+                instruction(opcode, None),
+                instruction(Opcode::HALT, None),
+            ];
+            let mut circuit = circuit_for(program.clone());
+            for state in &mut circuit.trace[3..] {
+                state.stack[0] ^= 1;
+            }
+            assert!(!is_satisfied(recommit(circuit)), "forged {:?} result accepted", opcode);
+
+            let mut circuit = circuit_for(program);
+            for state in &mut circuit.trace[3..] {
+                state.flags ^= 1 << LESS_FLAG;
+            }
+            assert!(!is_satisfied(recommit(circuit)), "forged {:?} flags accepted", opcode);
+        }
+    }
+
+    /// Sums 3 + 2 + 1 with a JNZ loop, then takes a JLT on 1 < 2 to load the sum.
+    fn conditional_jump_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(0)),
+            instruction(Opcode::STORE, Some(1)),
+            instruction(Opcode::PUSH, Some(3)),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::STORE, Some(1)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::SUB, None),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::LOAD, Some(0)),
+            instruction(Opcode::JNZ, Some(4)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::LT, None),
+            instruction(Opcode::POP, None),
+            instruction(Opcode::JLT, Some(21)),
+            instruction(Opcode::PUSH, Some(0)),
+            instruction(Opcode::HALT, None),
+            instruction(Opcode::LOAD, Some(1)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn conditional_jumps_are_satisfied() {
+        let circuit = circuit_for(conditional_jump_program());
+        let last = circuit.trace.last().unwrap();
+        assert_eq!((last.pc, last.stack.clone()), (22, vec![6]));
+        assert!(is_satisfied(circuit));
+
+        for value in [2, 3] {
+            assert!(is_satisfied(circuit_for(equal_jump_program(value))));
+        }
+    }
+
+    /// Compares 2 with `value` and skips pushing a zero when they are equal.
+    fn equal_jump_program(value: u32) -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::PUSH, Some(value)),
+            instruction(Opcode::EQ, None),
+            instruction(Opcode::JEQ, Some(5)),
+            instruction(Opcode::PUSH, Some(0)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn wrong_conditional_branch_is_rejected() {
+        // JLT falls through although 1 < 2 set the less flag
+        let program = vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::LT, None),
+            instruction(Opcode::JLT, Some(5)),
+            instruction(Opcode::HALT, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut circuit = circuit_for(program.clone());
+        for state in &mut circuit.trace[4..] {
+            state.pc = 4;
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // JEQ jumps although 2 and 3 differ
+        let mut circuit = circuit_for(equal_jump_program(3));
+        let jumped = ProvableState {
+            pc: 5,
+            ..circuit.trace[4].clone()
+        };
+        for state in &mut circuit.trace[4..] {
+            *state = jumped.clone();
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // JNZ falls through on a non-zero value
+        let program = vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::JNZ, Some(3)),
+            instruction(Opcode::HALT, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut circuit = circuit_for(program);
+        for state in &mut circuit.trace[2..] {
+            state.pc = 2;
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    fn stack_manipulation_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::PUSH, Some(3)),
+            instruction(Opcode::ROT, None),
+            instruction(Opcode::OVER, None),
+            instruction(Opcode::SWAP, Some(3)),
+            instruction(Opcode::POP, None),
+            instruction(Opcode::DUP, Some(2)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn stack_manipulation_is_satisfied() {
+        let circuit = circuit_for(stack_manipulation_program());
+        let stacks: Vec<Vec<u32>> = circuit.trace[3..9].iter().map(|state| state.stack.clone()).collect();
+        assert_eq!(
+            stacks,
+            vec![
+                vec![1, 2, 3],
+                vec![2, 3, 1],
+                vec![2, 3, 1, 3],
+                vec![3, 3, 1, 2],
+                vec![3, 3, 1],
+                vec![3, 3, 1, 3],
+            ]
+        );
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn forged_stack_manipulation_is_rejected() {
+        // Each instruction ends a prefix of the program, whose stack is then forged
+        let cases = [(4, vec![3, 1, 2]), (5, vec![2, 3, 1, 1]), (6, vec![2, 3, 3, 1]), (8, vec![3, 3, 1, 1])];
+        for (len, forged) in cases {
+            let mut program = stack_manipulation_program();
+            program.truncate(len);
+            let opcode = program[len - 1].opcode;
+            program.push(instruction(Opcode::HALT, None));
+
+            let mut circuit = circuit_for(program);
+            for state in &mut circuit.trace[len..] {
+                state.stack = forged.clone();
+            }
+            assert!(!is_satisfied(recommit(circuit)), "forged {:?} accepted", opcode);
+        }
+    }
+
+    /// A circuit for a hand-written trace of `(pc, stack)` states, which the VM
+    /// would have refused to produce.
+    fn circuit_for_trace(program: Vec<Instruction>, states: Vec<(u32, Vec<u32>)>) -> ExecutionCircuit {
+        // Each state has used the gas of the instructions before it
+        let costs = GasCosts::default();
+        let mut gas_used = 0;
+        let trace: Vec<ProvableState> = states
+            .into_iter()
+            .map(|(pc, stack)| {
+                let state = ProvableState {
+                    pc,
+                    stack,
+                    gas_used,
+                    ..Default::default()
+                };
+                gas_used += program.get(pc as usize).map_or(0, |instruction| costs.cost(instruction.opcode));
+                state
+            })
+            .collect();
+        ExecutionCircuit::new(
+            BOUNDS,
+            &program,
+            &trace,
+            Fr::zero(),
+            PublicInputLayout::default(),
+            MemoryModel::Strict,
+            InputTapes::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn access_below_the_stack_is_rejected() {
+        // DUP 0 stays on the stack
+        let program = vec![
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::DUP, Some(0)),
+            instruction(Opcode::HALT, None),
+        ];
+        let states = vec![(0, vec![]), (1, vec![5]), (2, vec![5, 5]), (2, vec![5, 5])];
+        assert!(is_satisfied(circuit_for_trace(program, states)));
+
+        // ADD pops a padding zero under the only value on the stack
+        let program = vec![
+            instruction(Opcode::PUSH, Some(0)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let states = vec![(0, vec![]), (1, vec![0]), (2, vec![]), (2, vec![])];
+        assert!(!is_satisfied(circuit_for_trace(program, states)));
+
+        // DUP 1 copies the padding zero under the only value on the stack
+        let program = vec![
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::DUP, Some(1)),
+            instruction(Opcode::HALT, None),
+        ];
+        let states = vec![(0, vec![]), (1, vec![5]), (2, vec![5, 0]), (2, vec![5, 0])];
+        assert!(!is_satisfied(circuit_for_trace(program, states)));
+
+        // SWAP 0 would leave the stack as it is
+        let program = vec![
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::SWAP, Some(0)),
+            instruction(Opcode::HALT, None),
+        ];
+        let states = vec![(0, vec![]), (1, vec![5]), (2, vec![5]), (2, vec![5])];
+        assert!(!is_satisfied(circuit_for_trace(program, states)));
+    }
+
+    /// 3^2 + (2^2 + 1), where the second subroutine calls the first.
+    fn subroutine_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(3)),
+            instruction(Opcode::CALL, Some(6)),
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::CALL, Some(9)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::HALT, None),
+            // square
+            instruction(Opcode::DUP, Some(0)),
+            instruction(Opcode::MUL, None),
+            instruction(Opcode::RET, None),
+            // square plus one
+            instruction(Opcode::CALL, Some(6)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::RET, None),
+        ]
+    }
+
+    #[test]
+    fn subroutines_are_satisfied() {
+        let circuit = circuit_for(subroutine_program());
+        let depths: Vec<usize> = circuit.trace.iter().map(|state| state.call_stack.len()).collect();
+        assert_eq!(depths.iter().max(), Some(&2));
+        assert_eq!(circuit.trace.last().unwrap().stack, vec![14]);
+
+        // Nesting deeper than the bounds allow does not fit the circuit
+        let bounds = CircuitBounds { max_call_depth: 1, ..BOUNDS };
+        assert!(bounds.check(&circuit.program, &circuit.trace).is_err());
+        assert!(is_satisfied(circuit));
+    }
+
+    /// CALL 3, then RET to 1 and PUSH 7.
+    fn call_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::CALL, Some(3)),
+            instruction(Opcode::PUSH, Some(7)),
+            instruction(Opcode::HALT, None),
+            instruction(Opcode::RET, None),
+        ]
+    }
+
+    #[test]
+    fn forged_return_is_rejected() {
+        // RET skips the PUSH the return address points at
+        let mut circuit = circuit_for(call_program());
+        circuit.trace.truncate(3);
+        circuit.trace[2].pc = 2;
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // CALL pushes the address of the HALT, so the RET above looks consistent
+        let mut circuit = circuit_for(call_program());
+        circuit.trace.truncate(3);
+        circuit.trace[1].call_stack = vec![2];
+        circuit.trace[2].pc = 2;
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // RET on an empty call stack returns to the padding zero
+        let program = vec![instruction(Opcode::RET, None)];
+        assert!(!is_satisfied(circuit_for_trace(program, vec![(0, vec![]), (0, vec![])])));
+    }
+
+    /// Stores 5, 6 and 7 at addresses 0, 1 and 2 and sums them through pointers.
+    fn indirect_memory_program() -> Vec<Instruction> {
+        let mut program = Vec::new();
+        for (address, value) in [(0, 5), (1, 6), (2, 7)] {
+            program.push(instruction(Opcode::PUSH, Some(value)));
+            program.push(instruction(Opcode::PUSH, Some(address)));
+            program.push(instruction(Opcode::STOREI, None));
+        }
+        program.push(instruction(Opcode::PUSH, Some(0)));
+        program.push(instruction(Opcode::LOADI, None));
+        for address in [1, 2] {
+            program.push(instruction(Opcode::PUSH, Some(address)));
+            program.push(instruction(Opcode::LOADI, None));
+            program.push(instruction(Opcode::ADD, None));
+        }
+        program.push(instruction(Opcode::HALT, None));
+        program
+    }
+
+    #[test]
+    fn indirect_memory_access_is_satisfied() {
+        let circuit = circuit_for(indirect_memory_program());
+        let last = circuit.trace.last().unwrap();
+        assert_eq!(last.stack, vec![18]);
+        assert_eq!(last.heap, HashMap::from([(0, 5), (1, 6), (2, 7)]));
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn forged_indirect_memory_access_is_rejected() {
+        // LOADI 1 claims the value stored at address 2
+        let mut program = indirect_memory_program();
+        program.truncate(13);
+        program.push(instruction(Opcode::HALT, None));
+        let mut circuit = circuit_for(program);
+        assert_eq!(circuit.trace[13].stack, vec![5, 6]);
+        for state in &mut circuit.trace[13..] {
+            state.stack = vec![5, 7];
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // STOREI writes 6 to address 3 instead of 1, which the final heap gives away
+        let mut program = indirect_memory_program();
+        program.truncate(6);
+        program.push(instruction(Opcode::HALT, None));
+        let mut circuit = circuit_for(program);
+        circuit.public_layout.final_heap = vec![1, 3];
+        assert!(is_satisfied(circuit.clone()));
+        for state in &mut circuit.trace[6..] {
+            state.heap = HashMap::from([(0, 5), (3, 6)]);
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    #[test]
+    fn tampered_heap_read_is_rejected() {
+        // Address 3 is overwritten, the LOAD returns the stale first value
+        let program = vec![
+            instruction(Opcode::PUSH, Some(5)),
+            instruction(Opcode::STORE, Some(3)),
+            instruction(Opcode::PUSH, Some(6)),
+            instruction(Opcode::STORE, Some(3)),
+            instruction(Opcode::LOAD, Some(3)),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut circuit = circuit_for(program.clone());
+        assert_eq!(circuit.trace[5].stack, vec![6]);
+        assert!(is_satisfied(circuit.clone()));
+        for state in &mut circuit.trace[5..] {
+            state.stack = vec![5];
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // A LOAD of a cell nothing was stored to
+        let mut circuit = circuit_for(program);
+        circuit.program[4] = instruction(Opcode::LOAD, Some(4));
+        assert!(!is_satisfied(circuit));
+    }
+
+    fn input_program() -> Vec<Instruction> {
+        // (public 3 + secret 5) * public 4
+        vec![
+            instruction(Opcode::READ, None),
+            instruction(Opcode::READS, None),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::READ, None),
+            instruction(Opcode::MUL, None),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    fn input_tapes() -> InputTapes {
+        InputTapes {
+            public: vec![3, 4],
+            secret: vec![5],
+        }
+    }
+
+    #[test]
+    fn input_tapes_are_read() {
+        let circuit = circuit_with(input_program(), MemoryModel::Strict, input_tapes());
+        assert_eq!(circuit.trace.last().unwrap().stack, vec![32]);
+        assert_eq!(
+            circuit.public_inputs().input_tape,
+            commitment::commitment_to_bytes(commitment::input_commitment(&[3, 4]))
+        );
+        assert!(is_satisfied(circuit.clone()));
+
+        // The secret tape is not public, but the trace must still read what it holds
+        let mut other = circuit.clone();
+        other.inputs.secret = vec![6];
+        assert_eq!(other.public_inputs(), circuit.public_inputs());
+        assert!(!is_satisfied(other));
+
+        let mut vm = ProvableVM::new();
+        assert_eq!(
+            vm.run_program(&input_program(), &InputTapes { secret: Vec::new(), ..input_tapes() }, "/dev/null"),
+            Err(VmError::InputExhausted { pc: 1, opcode: Opcode::READS })
+        );
+    }
+
+    #[test]
+    fn trace_commitment_hides_the_secret_tape() {
+        let circuit = circuit_with(input_program(), MemoryModel::Strict, input_tapes());
+        let secret = InputTapes {
+            secret: vec![6],
+            ..input_tapes()
+        };
+        let other = circuit_with(input_program(), MemoryModel::Strict, secret);
+        assert_ne!(other.trace.last().unwrap().stack, circuit.trace.last().unwrap().stack);
+
+        // Both runs expose the same values but for their blinded trace commitments
+        let unlinked = |circuit: &ExecutionCircuit| PublicInputs {
+            trace_commitment: Vec::new(),
+            ..circuit.public_inputs()
+        };
+        assert_eq!(unlinked(&circuit), unlinked(&other));
+
+        // Rerunning the program on the right guess does not reproduce the commitment
+        let guess = circuit_with(input_program(), MemoryModel::Strict, input_tapes());
+        assert_eq!(guess.trace.last().unwrap().stack, circuit.trace.last().unwrap().stack);
+        assert_ne!(guess.trace_commitment, circuit.trace_commitment);
+        let unblinded = commitment::trace_commitment(&guess.trace, &BOUNDS, Fr::zero());
+        assert_ne!(commitment::commitment_to_bytes(unblinded), circuit.trace_commitment);
+
+        assert!(is_satisfied(circuit));
+        assert!(is_satisfied(other));
+    }
+
+    #[test]
+    fn forged_input_reads_are_rejected() {
+        let circuit = circuit_with(input_program(), MemoryModel::Strict, input_tapes());
+
+        // The trace reads 4 from a public tape that holds 5 there
+        let mut forged = circuit.clone();
+        forged.inputs.public = vec![3, 5];
+        assert!(!is_satisfied(forged));
+
+        // The second READ reads the first word again
+        let mut forged = circuit.clone();
+        for state in &mut forged.trace[4..] {
+            state.public_read = 1;
+        }
+        forged.trace[4].stack = vec![8, 3];
+        forged.trace[5].stack = vec![24];
+        forged.trace[6].stack = vec![24];
+        assert!(!is_satisfied(recommit(forged)));
+
+        // Reading past the end of the public tape
+        let mut forged = circuit;
+        forged.inputs.public = vec![3];
+        assert!(!is_satisfied(forged));
+    }
+
+    #[test]
+    fn output_is_public() {
+        let program = vec![
+            instruction(Opcode::READ, None),
+            instruction(Opcode::WRITE, None),
+            instruction(Opcode::PUSH, Some(7)),
+            instruction(Opcode::WRITE, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let inputs = InputTapes {
+            public: vec![3],
+            secret: Vec::new(),
+        };
+        let mut vm = ProvableVM::new();
+        assert_eq!(vm.run_program(&program, &inputs, "/dev/null"), Ok(vec![3, 7]));
+
+        let circuit = circuit_with(program.clone(), MemoryModel::Strict, inputs.clone());
+        assert_eq!(circuit.public_inputs().output, vec![3, 7]);
+        assert!(is_satisfied(circuit.clone()));
+
+        // The second WRITE leaves the output tape alone
+        let mut forged = circuit;
+        for state in &mut forged.trace[4..] {
+            state.written = 1;
+        }
+        assert!(!is_satisfied(recommit(forged)));
+
+        // More output than the circuit exposes
+        let mut chatty = program;
+        chatty.insert(4, instruction(Opcode::WRITE, None));
+        chatty.insert(4, instruction(Opcode::PUSH, Some(8)));
+        let mut vm = ProvableVM::new();
+        vm.run_program(&chatty, &inputs, "/dev/null").unwrap();
+        let circuit = ExecutionCircuit::new(
+            BOUNDS,
+            &chatty,
+            &vm.trace,
+            Fr::zero(),
+            PublicInputLayout::default(),
+            MemoryModel::Strict,
+            inputs,
+        );
+        assert!(circuit.is_err());
+    }
+
+    #[test]
+    fn gas_is_metered() {
+        let costs = GasCosts::default();
+        let expected: u64 = arithmetic_program().iter().map(|i| costs.cost(i.opcode)).sum();
+        let mut circuit = circuit_for(arithmetic_program());
+        circuit.public_layout.final_gas = true;
+        assert_eq!(circuit.public_inputs().final_gas, Some(expected));
+        assert!(is_satisfied(circuit.clone()));
+
+        // The trace claims a step was cheaper than it is
+        let mut forged = circuit.clone();
+        for state in &mut forged.trace[3..] {
+            state.gas_used -= 1;
+        }
+        assert!(!is_satisfied(recommit(forged)));
+
+        // The circuit charges by a different table than the run did
+        let mut repriced = circuit;
+        repriced.gas_costs.set(Opcode::ADD, 5);
+        assert!(!is_satisfied(repriced));
+    }
+
+    #[test]
+    fn ill_fitting_circuits_fail_synthesis() {
+        let synthesize = |circuit: ExecutionCircuit| circuit.generate_constraints(ConstraintSystem::<Fr>::new_ref());
+
+        let mut empty = circuit_for(arithmetic_program());
+        empty.trace.clear();
+        assert_eq!(empty.validate(), Err(CircuitError::EmptyTrace));
+        assert_eq!(synthesize(empty), Err(SynthesisError::Unsatisfiable));
+
+        let mut deep = circuit_for(arithmetic_program());
+        deep.trace[3].stack = vec![0; BOUNDS.max_stack + 1];
+        assert_eq!(deep.validate(), Err(CircuitError::StackTooDeep { depth: 5, max: 4 }));
+        assert_eq!(synthesize(deep), Err(SynthesisError::Unsatisfiable));
+
+        let mut long = circuit_for(arithmetic_program());
+        long.program = vec![instruction(Opcode::HALT, None); BOUNDS.max_program + 1];
+        assert_eq!(long.validate(), Err(CircuitError::ProgramTooLong { len: 25, max: 24 }));
+        assert_eq!(synthesize(long), Err(SynthesisError::Unsatisfiable));
+    }
+
+    fn uninitialized_load_program() -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::LOAD, Some(5)),
+            instruction(Opcode::PUSH, Some(9)),
+            instruction(Opcode::LOADI, None),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::HALT, None),
+        ]
+    }
+
+    #[test]
+    fn zero_initialized_memory_reads_zero() {
+        let program = uninitialized_load_program();
+        let mut strict = ProvableVM::new();
+        assert_eq!(
+            strict.run_program(&program, &InputTapes::default(), "/dev/null"),
+            Err(VmError::UnknownAddress {
+                pc: 0,
+                opcode: Opcode::LOAD,
+                address: 5
+            })
+        );
+
+        let circuit = circuit_with(program, MemoryModel::Zeroed, InputTapes::default());
+        assert_eq!(circuit.trace.last().unwrap().stack, vec![0]);
+        assert!(circuit.public_inputs().zero_initialized);
+        assert!(is_satisfied(circuit.clone()));
+
+        // The same execution under a strict memory model
+        let mut forged = circuit.clone();
+        forged.memory = MemoryModel::Strict;
+        assert!(!is_satisfied(forged));
+
+        // An uninitialised cell reads as anything but zero
+        let mut forged = circuit;
+        for state in &mut forged.trace[1..] {
+            state.stack[0] = 4;
+        }
+        forged.trace.last_mut().unwrap().stack = vec![4];
+        assert!(!is_satisfied(recommit(forged)));
+    }
+
+    #[test]
+    fn memory_image_is_loaded() {
+        let image = MemoryModel::Image(BTreeMap::from([(5, 7), (9, 1)]));
+        let circuit = circuit_with(uninitialized_load_program(), image.clone(), InputTapes::default());
+        assert_eq!(circuit.trace.last().unwrap().stack, vec![8]);
+        assert_eq!(
+            circuit.public_inputs().memory_image,
+            commitment::commitment_to_bytes(commitment::image_commitment(&[(5, 7), (9, 1)]))
+        );
+        assert!(is_satisfied(circuit.clone()));
+
+        // The trace loads values the committed image does not hold
+        let mut forged = circuit.clone();
+        forged.memory = MemoryModel::Image(BTreeMap::from([(5, 7), (9, 2)]));
+        assert!(!is_satisfied(forged));
+
+        let mut large = BTreeMap::from([(5, 7), (9, 1), (10, 0)]);
+        let initial = |heap: &BTreeMap<u32, u32>| ProvableState {
+            heap: heap.iter().map(|(&address, &value)| (address, value)).collect(),
+            ..Default::default()
+        };
+        let new = |trace: &[ProvableState], memory: MemoryModel| {
+            ExecutionCircuit::new(
+                BOUNDS,
+                &[],
+                trace,
+                Fr::zero(),
+                PublicInputLayout::default(),
+                memory,
+                InputTapes::default(),
+            )
+        };
+        assert!(new(&[initial(&large)], MemoryModel::Image(large.clone())).is_err());
+        large.remove(&10);
+        assert!(new(&[initial(&large)], MemoryModel::Image(large.clone())).is_ok());
+        assert!(new(&[initial(&large)], MemoryModel::Zeroed).is_err());
+        assert!(new(&[initial(&BTreeMap::new())], image).is_err());
+    }
+
+    #[test]
+    fn heap_spans_every_word_address() {
+        let program = vec![
+            instruction(Opcode::PUSH, Some(42)),
+            instruction(Opcode::PUSH, Some(u32::MAX)),
+            instruction(Opcode::STOREI, None),
+            instruction(Opcode::PUSH, Some(u32::MAX)),
+            instruction(Opcode::LOADI, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut circuit = circuit_for(program);
+        circuit.public_layout.final_heap = vec![u32::MAX, 0];
+        assert_eq!(
+            circuit.public_inputs().final_heap,
+            vec![(u32::MAX, Some(42)), (0, None)]
+        );
+        assert!(is_satisfied(circuit));
+    }
+
+    #[test]
+    fn public_heap_cells_are_bound() {
+        let layout = PublicInputLayout {
+            initial_heap: vec![3],
+            final_heap: vec![0, 3],
+            ..PublicInputLayout::default()
+        };
+        let mut circuit = circuit_for(arithmetic_program());
+        circuit.public_layout = layout.clone();
+        assert_eq!(circuit.public_inputs().final_heap, vec![(0, Some(30)), (3, None)]);
+        assert!(is_satisfied(circuit.clone()));
+
+        // Claiming the STOREd cell was never written, or that another one was
+        let mut forged = circuit.clone();
+        forged.trace.last_mut().unwrap().heap = HashMap::from([(0, 31)]);
+        assert!(!is_satisfied(forged));
+        let mut forged = circuit.clone();
+        forged.trace.last_mut().unwrap().heap = HashMap::from([(0, 30), (3, 1)]);
+        assert!(!is_satisfied(forged));
+
+        // A public initial cell is only present if the public inputs say so
+        let mut forged = circuit;
+        forged.trace[0].heap = HashMap::from([(3, 7)]);
+        let forged = recommit(forged);
+        assert_eq!(forged.public_inputs().initial_heap, vec![(3, Some(7))]);
+        assert_eq!(forged.public_inputs().final_heap, vec![(0, Some(30)), (3, None)]);
+        assert!(!is_satisfied(forged));
+
+        let circuit = ExecutionCircuit::new(
+            BOUNDS,
+            &arithmetic_program(),
+            &[ProvableState::default()],
+            Fr::zero(),
+            PublicInputLayout {
+                final_heap: vec![1, 2, 1],
+                ..layout
+            },
+            MemoryModel::Strict,
+            InputTapes::default(),
+        );
+        assert!(circuit.is_err());
+    }
+
+    #[test]
+    fn empty_final_stack_is_told_apart_from_a_zero_top() {
+        let layout = PublicInputLayout {
+            final_stack_top: true,
+            ..PublicInputLayout::default()
+        };
+        let zero = vec![instruction(Opcode::PUSH, Some(0)), instruction(Opcode::HALT, None)];
+        let mut empty = zero.clone();
+        empty.insert(1, instruction(Opcode::POP, None));
+
+        // The top is exposed last, as a (present, value) pair
+        for (program, top, present) in [(zero, Some(0), Fr::one()), (empty, None, Fr::zero())] {
+            let mut circuit = circuit_for(program);
+            circuit.public_layout = layout.clone();
+            let public_inputs = circuit.public_inputs();
+            assert_eq!(public_inputs.final_stack_top, Some(top));
+            let elements = public_inputs.to_field_elements(&BOUNDS);
+            assert_eq!(elements[elements.len() - 2..], [present, Fr::zero()]);
+            assert!(is_satisfied(circuit));
+        }
+    }
+
+    /// Constraint cost of every part of a step. Each instruction pays for decoding,
+    /// the stack and its own part, but as the circuit is universal every step
+    /// carries all of them. Run with
+    /// `cargo test constraint_cost_per_op -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn constraint_cost_per_op() {
+        let circuit = circuit_for(vec![instruction(Opcode::HALT, None)]);
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let (program, _) = ProgramVars::alloc(&cs, &circuit.bounds, &circuit.program).unwrap();
+        let trace = circuit.bounds.pad_trace(&circuit.trace);
+        let prev = StateVars::alloc(&cs, &circuit.bounds, &trace[0]).unwrap();
+        let next = StateVars::alloc(&cs, &circuit.bounds, &trace[1]).unwrap();
+        let tapes = Tapes {
+            public: TapeVars::alloc(&cs, &circuit.bounds, &[]).unwrap(),
+            secret: TapeVars::alloc(&cs, &circuit.bounds, &[]).unwrap(),
+            output: TapeVars::new(&cs, vec![Variable::One; circuit.bounds.max_output], &[]).unwrap(),
+        };
+
+        let mut costs = Vec::new();
+        let mut measure = |part: &'static str, ops: &'static str| {
+            costs.push((part, ops, cs.num_constraints()));
+        };
+        measure("", "");
+        let step = Step::decode(&cs, &program, &prev, &next, &trace[0], &trace[1]).unwrap();
+        measure("fetch and decode", "all");
+        enforce_stack(&step).unwrap();
+        measure("stack", "all, OVER ROT");
+        enforce_stack_access(&step).unwrap();
+        measure("stack access", "DUP SWAP");
+        let bits = OperandBits::decompose(&step).unwrap();
+        measure("operand bits", "bitwise, shifts, comparisons");
+        let shift = enforce_bitwise(&step, &bits).unwrap();
+        measure("bitwise", "AND OR XOR NOT SHL SHR");
+        let carry = enforce_arithmetic(&step, shift).unwrap();
+        measure("arithmetic", "ADD SUB MUL DIVU MODU SHL SHR");
+        let (equal, less) = enforce_comparison(&step, &bits).unwrap();
+        measure("comparison", "EQ LT GT SLT SGT");
+        enforce_flags(&step, carry, equal, less).unwrap();
+        measure("flags", "ADD SUB MUL and comparisons");
+        let access = enforce_memory(&step, 1).unwrap();
+        measure("memory", "LOAD STORE LOADI STOREI");
+        enforce_tapes(&step, &tapes).unwrap();
+        measure("tapes", "READ READS WRITE");
+        enforce_gas(&step, &GasCosts::default()).unwrap();
+        measure("gas", "all");
+        enforce_calls(&step).unwrap();
+        measure("call stack", "CALL RET");
+        enforce_control_flow(&step, &program).unwrap();
+        measure("control flow", "JMP JZ JNZ JLT JEQ CALL RET HALT");
+        // Each step adds an entry to the memory log, checked once for the whole trace
+        let padding = AccessVars::constant(Access::step(2, None, 0, &trace[1], &trace[2]));
+        memory::enforce_consistency(&cs, &[], &[access, padding]).unwrap();
+        measure("memory log", "two entries");
+
+        println!("Constraint cost per step with {:?}", circuit.bounds);
+        for window in costs.windows(2) {
+            let (part, ops, after) = window[1];
+            println!("{:>16}: {:>5} constraints ({})", part, after - window[0].2, ops);
+        }
+        println!("{:>16}: {:>5} constraints", "total", costs.last().unwrap().2 - costs[0].2);
+    }
+
+    #[test]
+    fn forged_load_is_rejected() {
+        // LOAD 0 claims to have read 7 instead of the stored 30
+        let mut forged = arithmetic_program();
+        forged[4] = instruction(Opcode::PUSH, Some(7));
+        assert!(!is_satisfied(splice(arithmetic_program(), forged, 5)));
+    }
+
+    #[test]
+    fn forged_heap_write_is_rejected() {
+        // STORE 0 pops 30 but the heap afterwards holds 99
+        let mut forged = arithmetic_program();
+        forged[1] = instruction(Opcode::PUSH, Some(89));
+        assert!(!is_satisfied(splice(arithmetic_program(), forged, 4)));
+    }
+
+    #[test]
+    fn forged_stack_pointer_is_rejected() {
+        let mut circuit = circuit_for(arithmetic_program());
+        // The state after PUSH 20 silently drops the element below it
+        circuit.trace[2].stack = vec![20];
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    #[test]
+    fn skipped_instruction_is_rejected() {
+        let mut circuit = circuit_for(arithmetic_program());
+        // Drop the state between PUSH 10 and PUSH 20
+        circuit.trace.remove(1);
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    #[test]
+    fn wrong_branch_is_rejected() {
+        let program = vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::JZ, Some(3)),
+            instruction(Opcode::PUSH, Some(7)),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut circuit = circuit_for(program);
+        // Claim the JZ was taken although the popped value is 1
+        let mut taken = circuit.trace[2].clone();
+        taken.pc = 3;
+        circuit.trace.truncate(2);
+        circuit.trace.push(taken.clone());
+        circuit.trace.push(taken);
+        assert!(!is_satisfied(recommit(circuit)));
+
+        // Claim the JZ fell through although the popped value is 0
+        let program = vec![
+            instruction(Opcode::PUSH, Some(0)),
+            instruction(Opcode::JZ, Some(3)),
+            instruction(Opcode::HALT, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut circuit = circuit_for(program);
+        assert_eq!(circuit.trace[2].pc, 3);
+        for state in &mut circuit.trace[2..] {
+            state.pc = 2;
+        }
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    #[test]
+    fn jump_outside_the_program_is_rejected() {
+        // JMP 2 lands on the HALT padding past the end of a two-instruction program
+        let mut circuit = circuit_for(vec![instruction(Opcode::JMP, Some(1)), instruction(Opcode::HALT, None)]);
+        circuit.program[0].operand = Some(2);
+        circuit.trace[1].pc = 2;
+        circuit.trace[2].pc = 2;
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+
+    #[test]
+    fn mismatched_trace_commitment_is_rejected() {
+        let mut circuit = circuit_for(arithmetic_program());
+        circuit.trace_commitment = circuit_for(countdown_program()).trace_commitment;
+        assert!(!is_satisfied(circuit));
+    }
+
+    #[test]
+    fn mismatched_program_is_rejected() {
+        // Same trace, but the committed program pushes 11 first
+        let mut circuit = circuit_for(arithmetic_program());
+        circuit.program[0].operand = Some(11);
+        assert!(!is_satisfied(circuit));
+    }
+
+    #[test]
+    fn truncated_trace_is_rejected() {
+        let mut circuit = circuit_for(arithmetic_program());
+        circuit.trace.truncate(4);
+        assert!(!is_satisfied(recommit(circuit)));
+    }
+}
@@ -0,0 +1,203 @@
+use std::sync::OnceLock;
+use ark_bls12_381::Fr;
+use ark_crypto_primitives::sponge::constraints::CryptographicSpongeVar;
+use ark_crypto_primitives::sponge::poseidon::constraints::PoseidonSpongeVar;
+use ark_crypto_primitives::sponge::poseidon::{find_poseidon_ark_and_mds, PoseidonConfig, PoseidonSponge};
+use ark_crypto_primitives::sponge::CryptographicSponge;
+use ark_ff::{BigInteger, PrimeField};
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_relations::r1cs::{ConstraintSystemRef, SynthesisError, Variable};
+use crate::circuit::{pack, CircuitBounds, WORD_BITS};
+use crate::gadgets;
+use crate::vm::ProvableState;
+
+// Poseidon instance over the BLS12-381 scalar field: width 3 (rate 2, capacity 1),
+// x^17 S-boxes, 8 full and 31 partial rounds, constants from the Grain LFSR.
+const RATE: usize = 2;
+const ALPHA: u64 = 17;
+const FULL_ROUNDS: usize = 8;
+const PARTIAL_ROUNDS: usize = 31;
+
+/// Seeds the program hash chain, bumped whenever the instruction encoding changes.
+const PROGRAM_DOMAIN: &[u8] = b"provable-vm/program/v2";
+
+/// Seeds the memory image hash chain.
+const IMAGE_DOMAIN: &[u8] = b"provable-vm/memory-image/v1";
+
+/// Seeds the public input tape hash chain.
+const INPUT_DOMAIN: &[u8] = b"provable-vm/input-tape/v1";
+
+/// Seeds the fingerprint of a key pair.
+const KEY_DOMAIN: &[u8] = b"provable-vm/key/v1";
+
+static POSEIDON_CONFIG: OnceLock<PoseidonConfig<Fr>> = OnceLock::new();
+
+pub fn poseidon_config() -> &'static PoseidonConfig<Fr> {
+    POSEIDON_CONFIG.get_or_init(|| {
+        let (ark, mds) = find_poseidon_ark_and_mds::<Fr>(
+            Fr::MODULUS_BIT_SIZE as u64,
+            RATE,
+            FULL_ROUNDS as u64,
+            PARTIAL_ROUNDS as u64,
+            0,
+        );
+        PoseidonConfig::new(FULL_ROUNDS, PARTIAL_ROUNDS, ALPHA, mds, ark, RATE, 1)
+    })
+}
+
+/// Poseidon sponge hash of a short list of field elements.
+pub fn poseidon_hash(inputs: &[Fr]) -> Fr {
+    let mut sponge = PoseidonSponge::new(poseidon_config());
+    sponge.absorb(&inputs);
+    sponge.squeeze_field_elements::<Fr>(1)[0]
+}
+
+/// In-circuit counterpart of `poseidon_hash`.
+pub fn poseidon_hash_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    inputs: &[FpVar<Fr>],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let mut sponge = PoseidonSpongeVar::new(cs.clone(), poseidon_config());
+    sponge.absorb(&inputs)?;
+    Ok(sponge.squeeze_field_elements(1)?.remove(0))
+}
+
+pub fn program_domain() -> Fr {
+    Fr::from_le_bytes_mod_order(PROGRAM_DOMAIN)
+}
+
+pub fn image_domain() -> Fr {
+    Fr::from_le_bytes_mod_order(IMAGE_DOMAIN)
+}
+
+pub fn input_domain() -> Fr {
+    Fr::from_le_bytes_mod_order(INPUT_DOMAIN)
+}
+
+/// `h = domain; h = poseidon(h, entry)` for every entry in order.
+fn chain_commitment(domain: Fr, entries: impl IntoIterator<Item = Fr>) -> Fr {
+    entries
+        .into_iter()
+        .fold(domain, |hash, entry| poseidon_hash(&[hash, entry]))
+}
+
+/// Commitment to a program given its encoded instructions: a hash chain over
+/// every instruction in order.
+///
+/// Chaining keeps the hash independent of the circuit bounds, so the loader can
+/// compute it without knowing how much the program will be padded.
+pub fn program_commitment(encoded: &[u64]) -> Fr {
+    chain_commitment(program_domain(), encoded.iter().map(|&instruction| Fr::from(instruction)))
+}
+
+/// Commitment to a memory image: a hash chain over `address + 2^32 value` for
+/// every cell, by ascending address.
+pub fn image_commitment(cells: &[(u32, u32)]) -> Fr {
+    chain_commitment(
+        image_domain(),
+        cells
+            .iter()
+            .map(|&(address, value)| pack(&[address as u64, value as u64], WORD_BITS)),
+    )
+}
+
+/// Commitment to an input tape: a hash chain over its words in order.
+pub fn input_commitment(tape: &[u32]) -> Fr {
+    chain_commitment(input_domain(), tape.iter().map(|&word| Fr::from(word)))
+}
+
+/// Recomputes `program_commitment` in-circuit over a fixed number of witnessed
+/// `entries`. Only the entries flagged by the boolean `mask` are chained in, so
+/// the hash does not depend on how far the program was padded.
+pub fn program_commitment_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    entries: &[Variable],
+    mask: &[Variable],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    chain_commitment_gadget(cs, program_domain(), entries, mask)
+}
+
+/// Recomputes `image_commitment` in-circuit over the packed cells flagged by `mask`.
+pub fn image_commitment_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    entries: &[Variable],
+    mask: &[Variable],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    chain_commitment_gadget(cs, image_domain(), entries, mask)
+}
+
+/// Recomputes `input_commitment` in-circuit over the words flagged by `mask`.
+pub fn input_commitment_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    words: &[Variable],
+    mask: &[Variable],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    chain_commitment_gadget(cs, input_domain(), words, mask)
+}
+
+fn chain_commitment_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    domain: Fr,
+    entries: &[Variable],
+    mask: &[Variable],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let mut hash = FpVar::Constant(domain);
+    for (&entry, &included) in entries.iter().zip(mask) {
+        let chained = poseidon_hash_gadget(cs, &[hash.clone(), gadgets::to_fp_var(cs, entry)])?;
+        let included = gadgets::to_fp_var(cs, included);
+        hash = &hash + included * (chained - &hash);
+    }
+    Ok(hash)
+}
+
+/// Poseidon commitment to a trace, hidden by a random `blinding`.
+///
+/// The trace is padded to `bounds.max_steps + 1` states; the sponge absorbs the
+/// bounds, the blinding and then the field encoding of every state, exactly as
+/// `trace_commitment_gadget` does in-circuit. Without the blinding, anyone could
+/// guess a secret input, rerun the program and compare commitments.
+pub fn trace_commitment(trace: &[ProvableState], bounds: &CircuitBounds, blinding: Fr) -> Fr {
+    let mut sponge = PoseidonSponge::new(poseidon_config());
+    sponge.absorb(&bounds.header());
+    sponge.absorb(&blinding);
+    for state in bounds.pad_trace(trace) {
+        sponge.absorb(&bounds.encode_state(&state));
+    }
+    sponge.squeeze_field_elements::<Fr>(1)[0]
+}
+
+/// Commitment to a serialized key: the sponge absorbs the key domain, the length
+/// of `bytes` and then the bytes, 31 to a field element so that every chunk is
+/// below the modulus.
+pub fn key_commitment(bytes: &[u8]) -> Fr {
+    let mut sponge = PoseidonSponge::new(poseidon_config());
+    sponge.absorb(&[Fr::from_le_bytes_mod_order(KEY_DOMAIN), Fr::from(bytes.len() as u64)].as_slice());
+    let chunks: Vec<Fr> = bytes.chunks(31).map(Fr::from_le_bytes_mod_order).collect();
+    sponge.absorb(&chunks);
+    sponge.squeeze_field_elements::<Fr>(1)[0]
+}
+
+/// Serializes a commitment as the little-endian bytes of the field element.
+pub fn commitment_to_bytes(commitment: Fr) -> Vec<u8> {
+    commitment.into_bigint().to_bytes_le()
+}
+
+/// Recomputes `trace_commitment` over the witnessed blinding and state encodings.
+pub fn trace_commitment_gadget(
+    cs: &ConstraintSystemRef<Fr>,
+    header: &[Fr],
+    blinding: Variable,
+    states: &[Vec<Variable>],
+) -> Result<FpVar<Fr>, SynthesisError> {
+    let mut sponge = PoseidonSpongeVar::new(cs.clone(), poseidon_config());
+    let header: Vec<FpVar<Fr>> = header.iter().map(|&value| FpVar::Constant(value)).collect();
+    sponge.absorb(&header)?;
+    sponge.absorb(&gadgets::to_fp_var(cs, blinding))?;
+
+    for encoding in states {
+        let encoding: Vec<FpVar<Fr>> = encoding.iter().map(|&var| gadgets::to_fp_var(cs, var)).collect();
+        sponge.absorb(&encoding)?;
+    }
+
+    Ok(sponge.squeeze_field_elements(1)?.remove(0))
+}
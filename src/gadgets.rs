@@ -0,0 +1,105 @@
+use ark_bls12_381::Fr;
+use ark_ff::{Field, Zero};
+use ark_r1cs_std::fields::fp::{AllocatedFp, FpVar};
+use ark_relations::lc;
+use ark_relations::r1cs::{ConstraintSystemRef, LinearCombination, SynthesisError, Variable};
+
+/// Allocates a boolean witness that is 1 exactly when `value_var` is zero.
+///
+/// Uses the usual inverse trick: `value * inverse = 1 - is_zero` together with
+/// `value * is_zero = 0`, which leaves the prover no freedom in either case.
+pub fn is_zero(
+    cs: &ConstraintSystemRef<Fr>,
+    value_var: Variable,
+    value: Fr,
+) -> Result<Variable, SynthesisError> {
+    let inverse = value.inverse().unwrap_or(Fr::zero());
+    let inverse_var = cs.new_witness_variable(|| Ok(inverse))?;
+    let is_zero_var = cs.new_witness_variable(|| Ok(Fr::from(value.is_zero())))?;
+
+    cs.enforce_constraint(
+        lc!() + value_var,
+        lc!() + inverse_var,
+        lc!() + Variable::One - is_zero_var,
+    )?;
+    cs.enforce_constraint(lc!() + value_var, lc!() + is_zero_var, lc!())?;
+
+    Ok(is_zero_var)
+}
+
+/// Decomposes `value_var` into `num_bits` little-endian boolean witnesses.
+///
+/// Recombining the bits into `value_var` doubles as a range check
+/// `0 <= value < 2^num_bits`, which keeps field wrap-around out of word values.
+pub fn to_bits(
+    cs: &ConstraintSystemRef<Fr>,
+    value_var: Variable,
+    value: u64,
+    num_bits: usize,
+) -> Result<Vec<Variable>, SynthesisError> {
+    let mut bits = Vec::with_capacity(num_bits);
+    let mut recombined = lc!();
+    let mut coeff = Fr::from(1u64);
+
+    for i in 0..num_bits {
+        let bit = i < 64 && (value >> i) & 1 == 1;
+        let bit_var = cs.new_witness_variable(|| Ok(Fr::from(bit)))?;
+        enforce_boolean(cs, bit_var)?;
+
+        recombined += (coeff, bit_var);
+        coeff.double_in_place();
+        bits.push(bit_var);
+    }
+
+    cs.enforce_constraint(recombined, lc!() + Variable::One, lc!() + value_var)?;
+    Ok(bits)
+}
+
+/// Enforces `var * (1 - var) = 0`, i.e. `var` is 0 or 1.
+pub fn enforce_boolean(cs: &ConstraintSystemRef<Fr>, var: Variable) -> Result<(), SynthesisError> {
+    cs.enforce_constraint(lc!() + var, lc!() + Variable::One - var, lc!())
+}
+
+/// Allocates a witness for `a * b`, whose assigned value is `value`.
+pub fn product(
+    cs: &ConstraintSystemRef<Fr>,
+    a: LinearCombination<Fr>,
+    b: LinearCombination<Fr>,
+    value: Fr,
+) -> Result<Variable, SynthesisError> {
+    let product_var = cs.new_witness_variable(|| Ok(value))?;
+    cs.enforce_constraint(a, b, lc!() + product_var)?;
+    Ok(product_var)
+}
+
+/// Enforces `a = b` whenever the boolean `condition` is set: `condition * (a - b) = 0`.
+pub fn enforce_equal_if(
+    cs: &ConstraintSystemRef<Fr>,
+    condition: LinearCombination<Fr>,
+    a: LinearCombination<Fr>,
+    b: LinearCombination<Fr>,
+) -> Result<(), SynthesisError> {
+    cs.enforce_constraint(condition, a - b, lc!())
+}
+
+/// Enforces `a = b` for two linear combinations.
+pub fn enforce_equal(
+    cs: &ConstraintSystemRef<Fr>,
+    a: LinearCombination<Fr>,
+    b: LinearCombination<Fr>,
+) -> Result<(), SynthesisError> {
+    cs.enforce_constraint(a, lc!() + Variable::One, b)
+}
+
+/// Wraps a raw variable so it can be fed to `ark-r1cs-std` gadgets.
+pub fn to_fp_var(cs: &ConstraintSystemRef<Fr>, var: Variable) -> FpVar<Fr> {
+    FpVar::Var(AllocatedFp::new(cs.assigned_value(var), var, cs.clone()))
+}
+
+/// The linear combination an `ark-r1cs-std` field variable stands for.
+pub fn fp_var_lc(value: &FpVar<Fr>) -> LinearCombination<Fr> {
+    match value {
+        FpVar::Constant(constant) => lc!() + (*constant, Variable::One),
+        FpVar::Var(allocated) => lc!() + allocated.variable,
+    }
+}
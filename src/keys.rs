@@ -0,0 +1,288 @@
+use ark_bls12_381::Bls12_381;
+use ark_groth16::{ProvingKey, VerifyingKey};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, Compress, SerializationError, Validate};
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use crate::circuit::{CircuitBounds, CircuitShape};
+use crate::commitment::{commitment_to_bytes, key_commitment};
+use crate::public_inputs::PublicInputLayout;
+use crate::zk_proof::{self, ProverError};
+
+/// What a key file holds besides the key: the fingerprint of the key pair it
+/// belongs to and the shape of the circuit the pair was set up for.
+///
+/// The fingerprint is the `commitment::key_commitment` of the verifying key, which
+/// the proving key embeds, followed by the shape, so both files of a pair carry the
+/// same one, a proving key can always be matched to its verifying key and the
+/// shape cannot be swapped for another one.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    fingerprint: Vec<u8>,
+    shape: CircuitShape,
+    /// The serialized key. Proving keys are large and only ever produce proofs the
+    /// verifier checks, so they are stored uncompressed and loaded without
+    /// validating their points; verifying keys are compressed and validated.
+    key: Vec<u8>,
+}
+
+/// A key read from a key file, with what the file records about it.
+pub struct LoadedKey<K> {
+    pub key: K,
+    pub fingerprint: Vec<u8>,
+    pub shape: CircuitShape,
+}
+
+/// A key pair and the shape of the circuit it was set up for.
+pub struct KeyPair {
+    pub pk: ProvingKey<Bls12_381>,
+    pub vk: VerifyingKey<Bls12_381>,
+    pub shape: CircuitShape,
+}
+
+/// Why a key file could not be written or used.
+#[derive(Debug)]
+pub enum KeyError {
+    Io(io::Error),
+    /// The file is not a key file.
+    Format(String),
+    Serialization(SerializationError),
+    /// The fingerprint in the file is not the fingerprint of the key it holds.
+    Corrupted { path: String },
+    /// The proving and the verifying key belong to different setups.
+    PairMismatch { pk_path: String, vk_path: String },
+    /// The key was set up for other bounds or another public layout than the ones
+    /// it is used with, `found` is the shape its file records.
+    CircuitMismatch { path: String, found: Box<CircuitShape> },
+    /// Only one of the two files of a pair exists.
+    MissingKey { path: String },
+    Setup(ProverError),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Io(e) => write!(f, "{}", e),
+            KeyError::Format(e) => write!(f, "Not a key file: {}", e),
+            KeyError::Serialization(e) => write!(f, "Invalid key: {}", e),
+            KeyError::Corrupted { path } => write!(f, "Key in '{}' does not match its fingerprint", path),
+            KeyError::PairMismatch { pk_path, vk_path } => write!(
+                f,
+                "Proving key '{}' and verifying key '{}' come from different setups",
+                pk_path, vk_path
+            ),
+            KeyError::CircuitMismatch { path, found } => write!(
+                f,
+                "Key in '{}' was set up for a different circuit\n  bounds: {:?}\n  layout: {:?}",
+                path, found.bounds, found.layout
+            ),
+            KeyError::MissingKey { path } => write!(f, "Key '{}' is missing from its pair", path),
+            KeyError::Setup(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyError::Io(e) => Some(e),
+            KeyError::Serialization(e) => Some(e),
+            KeyError::Setup(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Fingerprint of the key pair `vk` belongs to, set up for a circuit of `shape`.
+pub fn fingerprint(vk: &VerifyingKey<Bls12_381>, shape: &CircuitShape) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    vk.serialize_compressed(&mut bytes)
+        .expect("Serializing into a Vec cannot fail");
+    bincode::serialize_into(&mut bytes, shape).expect("Serializing into a Vec cannot fail");
+    commitment_to_bytes(key_commitment(&bytes))
+}
+
+/// Writes the key pair `pk` belongs to, set up for a circuit of `shape`, to
+/// `pk_path` and `vk_path`.
+pub fn save_keys(pk_path: &str, vk_path: &str, pk: &ProvingKey<Bls12_381>, shape: &CircuitShape) -> Result<(), KeyError> {
+    let fingerprint = fingerprint(&pk.vk, shape);
+    write_key(pk_path, pk, Compress::No, &fingerprint, shape)?;
+    write_key(vk_path, &pk.vk, Compress::Yes, &fingerprint, shape)
+}
+
+/// Reads a proving key written by `save_keys`.
+pub fn load_proving_key(path: &str) -> Result<LoadedKey<ProvingKey<Bls12_381>>, KeyError> {
+    read_key(path, (Compress::No, Validate::No), |pk: &ProvingKey<Bls12_381>| &pk.vk)
+}
+
+/// Reads a verifying key written by `save_keys`.
+pub fn load_verifying_key(path: &str) -> Result<LoadedKey<VerifyingKey<Bls12_381>>, KeyError> {
+    read_key(path, (Compress::Yes, Validate::Yes), |vk| vk)
+}
+
+/// Sets up a key pair for the circuit with `bounds` and `layout`, see
+/// `zk_proof::setup`, and records its shape.
+pub fn generate_keys<R: RngCore + CryptoRng>(
+    bounds: CircuitBounds,
+    layout: &PublicInputLayout,
+    rng: &mut R,
+) -> Result<KeyPair, KeyError> {
+    let (pk, vk) = zk_proof::setup(bounds, layout, rng).map_err(KeyError::Setup)?;
+    Ok(KeyPair {
+        pk,
+        vk,
+        shape: zk_proof::circuit_shape(bounds, layout).map_err(KeyError::Setup)?,
+    })
+}
+
+/// Loads the key pair for the circuit with `bounds` and `layout` from `pk_path`
+/// and `vk_path`, or sets one up and saves it there if neither file exists yet, so
+/// setup only runs once per circuit. Loaded keys are matched to the circuit by the
+/// bounds and layout their files record, without synthesising it. Returns whether
+/// the pair was set up, along with it.
+pub fn load_or_setup<R: RngCore + CryptoRng>(
+    pk_path: &str,
+    vk_path: &str,
+    bounds: CircuitBounds,
+    layout: &PublicInputLayout,
+    rng: &mut R,
+) -> Result<(KeyPair, bool), KeyError> {
+    match (Path::new(pk_path).exists(), Path::new(vk_path).exists()) {
+        (true, true) => {
+            let pk = load_proving_key(pk_path)?;
+            let vk = load_verifying_key(vk_path)?;
+            for (path, found) in [(pk_path, &pk.shape), (vk_path, &vk.shape)] {
+                if found.bounds != bounds || found.layout != *layout {
+                    return Err(KeyError::CircuitMismatch {
+                        path: path.to_string(),
+                        found: Box::new(found.clone()),
+                    });
+                }
+            }
+            if pk.fingerprint != vk.fingerprint {
+                return Err(KeyError::PairMismatch {
+                    pk_path: pk_path.to_string(),
+                    vk_path: vk_path.to_string(),
+                });
+            }
+            let keys = KeyPair {
+                pk: pk.key,
+                vk: vk.key,
+                shape: pk.shape,
+            };
+            Ok((keys, false))
+        }
+        (false, false) => {
+            let keys = generate_keys(bounds, layout, rng)?;
+            save_keys(pk_path, vk_path, &keys.pk, &keys.shape)?;
+            Ok((keys, true))
+        }
+        (false, true) => Err(KeyError::MissingKey { path: pk_path.to_string() }),
+        (true, false) => Err(KeyError::MissingKey { path: vk_path.to_string() }),
+    }
+}
+
+fn write_key<K: CanonicalSerialize>(
+    path: &str,
+    key: &K,
+    compress: Compress,
+    fingerprint: &[u8],
+    shape: &CircuitShape,
+) -> Result<(), KeyError> {
+    let mut bytes = Vec::new();
+    key.serialize_with_mode(&mut bytes, compress).map_err(KeyError::Serialization)?;
+    let file = KeyFile {
+        fingerprint: fingerprint.to_vec(),
+        shape: shape.clone(),
+        key: bytes,
+    };
+    bincode::serialize_into(BufWriter::new(File::create(path).map_err(KeyError::Io)?), &file)
+        .map_err(|e| KeyError::Format(e.to_string()))
+}
+
+/// Reads the key in `path` and checks it and its shape against their fingerprint.
+/// `vk` finds the verifying key the fingerprint is taken of.
+fn read_key<K: CanonicalDeserialize>(
+    path: &str,
+    (compress, validate): (Compress, Validate),
+    vk: impl Fn(&K) -> &VerifyingKey<Bls12_381>,
+) -> Result<LoadedKey<K>, KeyError> {
+    let file: KeyFile = bincode::deserialize_from(BufReader::new(File::open(path).map_err(KeyError::Io)?))
+        .map_err(|e| KeyError::Format(e.to_string()))?;
+    let key = K::deserialize_with_mode(file.key.as_slice(), compress, validate).map_err(KeyError::Serialization)?;
+    if fingerprint(vk(&key), &file.shape) != file.fingerprint {
+        return Err(KeyError::Corrupted { path: path.to_string() });
+    }
+    Ok(LoadedKey {
+        key,
+        fingerprint: file.fingerprint,
+        shape: file.shape,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{temp_path, BOUNDS};
+    use rand_chacha::ChaCha20Rng;
+    use rand_core::SeedableRng;
+
+    #[test]
+    fn keys_are_set_up_once_and_checked_on_load() {
+        let pk_path = temp_path("provable-vm-keys.pk");
+        let vk_path = temp_path("provable-vm-keys.vk");
+        let other_pk_path = temp_path("provable-vm-keys-other.pk");
+        let other_vk_path = temp_path("provable-vm-keys-other.vk");
+        for path in [&pk_path, &vk_path, &other_pk_path, &other_vk_path] {
+            let _ = std::fs::remove_file(path);
+        }
+        let layout = PublicInputLayout::default();
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+
+        // The first call sets up, the second loads the same pair
+        let (keys, fresh) = load_or_setup(&pk_path, &vk_path, BOUNDS, &layout, &mut rng).unwrap();
+        let (loaded, loaded_fresh) = load_or_setup(&pk_path, &vk_path, BOUNDS, &layout, &mut rng).unwrap();
+        assert!(fresh && !loaded_fresh);
+        assert_eq!(loaded.pk, keys.pk);
+        assert_eq!(loaded.vk, keys.vk);
+        assert_eq!(load_verifying_key(&vk_path).unwrap().shape, keys.shape);
+
+        // Keys for another circuit are refused
+        let other_layout = PublicInputLayout {
+            final_pc: true,
+            ..PublicInputLayout::default()
+        };
+        let result = load_or_setup(&pk_path, &vk_path, BOUNDS, &other_layout, &mut rng);
+        assert!(matches!(result, Err(KeyError::CircuitMismatch { .. })));
+        let bounds = CircuitBounds { max_steps: 17, ..BOUNDS };
+        let result = load_or_setup(&pk_path, &vk_path, bounds, &layout, &mut rng);
+        assert!(matches!(result, Err(KeyError::CircuitMismatch { .. })));
+
+        // So are the halves of two different setups
+        load_or_setup(&other_pk_path, &other_vk_path, BOUNDS, &layout, &mut rng).unwrap();
+        let result = load_or_setup(&pk_path, &other_vk_path, BOUNDS, &layout, &mut rng);
+        assert!(matches!(result, Err(KeyError::PairMismatch { .. })));
+
+        // And a key relabelled with another pair's fingerprint
+        let mut file: KeyFile = bincode::deserialize_from(File::open(&vk_path).unwrap()).unwrap();
+        file.fingerprint = load_verifying_key(&other_vk_path).unwrap().fingerprint;
+        bincode::serialize_into(File::create(&vk_path).unwrap(), &file).unwrap();
+        let result = load_verifying_key(&vk_path);
+        assert!(matches!(result, Err(KeyError::Corrupted { .. })));
+
+        // Or with the shape of another circuit
+        let mut file: KeyFile = bincode::deserialize_from(File::open(&pk_path).unwrap()).unwrap();
+        file.shape.bounds.max_steps += 1;
+        bincode::serialize_into(File::create(&pk_path).unwrap(), &file).unwrap();
+        let result = load_proving_key(&pk_path);
+        assert!(matches!(result, Err(KeyError::Corrupted { .. })));
+
+        // A lone half is not completed with a fresh setup
+        std::fs::remove_file(&vk_path).unwrap();
+        let result = load_or_setup(&pk_path, &vk_path, BOUNDS, &layout, &mut rng);
+        assert!(matches!(result, Err(KeyError::MissingKey { .. })));
+    }
+}
@@ -0,0 +1,39 @@
+//! A stack VM whose executions are proven with Groth16.
+//!
+//! Programs are assembled with `assembler`, run by `vm::ProvableVM`, which records
+//! the trace, and proven against the universal `circuit::ExecutionCircuit` with
+//! `prover`, with keys persisted by `keys`. `verifier` checks a proof against the
+//! `public_inputs::PublicInputs` it claims.
+
+pub mod circuit;
+pub mod keys;
+pub mod public_inputs;
+pub mod vm;
+
+mod commitment;
+mod gadgets;
+mod memory;
+mod program_loader;
+#[cfg(test)]
+mod test_support;
+mod utils;
+mod zk_proof;
+
+/// Loading programs from `.prov` files and hashing them.
+pub mod assembler {
+    pub use crate::program_loader::{load_program, program_hash};
+}
+
+/// Key setup and proof generation.
+pub mod prover {
+    pub use crate::zk_proof::{circuit_shape, generate_proof, setup, ProverError};
+}
+
+/// Proof verification.
+pub mod verifier {
+    pub use crate::zk_proof::{verify_program_proof, verify_proof, VerifyError};
+}
+
+pub use circuit::{CircuitBounds, CircuitError, CircuitShape, ExecutionCircuit};
+pub use public_inputs::{PublicInputLayout, PublicInputs};
+pub use vm::{Instruction, Opcode, ProvableVM, VmError};
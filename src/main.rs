@@ -1,60 +1,246 @@
-mod vm;
-mod program_loader;
-mod utils;
-mod zk_proof;
-
-use vm::{ProvableVM, ExecutionCircuit};
-use program_loader::load_program;
-use utils::{convert_commitment_to_field, load_vk};
-use zk_proof::{verify_proof};
-use ark_bls12_381::{Fr, Bls12_381};
-use ark_groth16::{Groth16};
-use ark_snark::CircuitSpecificSetupSNARK;
+use provable_vm::assembler::load_program;
+use provable_vm::keys::{generate_keys, load_proving_key, load_verifying_key, save_keys};
+use provable_vm::verifier::{verify_program_proof, verify_proof};
+use provable_vm::vm::{InputTapes, ProvableVM};
+use provable_vm::{CircuitBounds, Instruction, PublicInputLayout, PublicInputs};
 use rand_chacha::ChaCha20Rng;
 use rand_core::SeedableRng;
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::process::ExitCode;
 
-fn main() {
+const USAGE: &str = "Usage:
+    provable-vm run <program> [--trace <file>] [--input <words>] [--secret <words>] [--bounds <words>]
+    provable-vm setup [--pk <file>] [--vk <file>] [--bounds <words>] [--force]
+    provable-vm prove <program> [--pk <file>] [--proof <file>] [--public <file>]
+                      [--trace <file>] [--input <words>] [--secret <words>]
+    provable-vm verify [<program>] [--vk <file>] [--proof <file>] [--public <file>]
 
-    // Path to files
-    let vk_path = "program.vk"; // Verifying key file path
-    let proof_path = "program.proof"; // Proof file path
+Words are comma separated, e.g. --input 3,4. Every file defaults to program.<flag>,
+e.g. program.pk. --bounds gives the circuit bounds as max steps, stack, program,
+call depth, image, input and output, which setup records in the keys; run pads
+the trace commitment it writes for them. setup refuses to replace existing keys
+without --force; prove proves for the bounds of the keys setup wrote. Exits with 1 when a command fails or a proof is
+invalid, 2 on bad usage.";
 
-    // Run the VM and generate proof
-    let mut vm = ProvableVM::new();
-    let program = load_program("program.prov").expect("Failed to load program");
+/// What the command line asks for. Files default to the ones an earlier command
+/// wrote with its defaults, so `setup`, `prove` and `verify` chain without flags.
+struct Options {
+    command: String,
+    program: Option<String>,
+    trace: String,
+    pk: String,
+    vk: String,
+    proof: String,
+    public: String,
+    inputs: InputTapes,
+    bounds: CircuitBounds,
+    /// Whether setup may replace existing keys.
+    force: bool,
+}
 
-    // Run program and generate trace
-    vm.run_program(&program, "program.trace").expect("Failed to execute program");
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let (command, mut rest) = args.split_first().ok_or("No command given")?;
+        let flags: &[&str] = match command.as_str() {
+            "run" => &["--trace", "--input", "--secret", "--bounds"],
+            "setup" => &["--pk", "--vk", "--bounds", "--force"],
+            "prove" => &["--pk", "--proof", "--public", "--trace", "--input", "--secret"],
+            "verify" => &["--vk", "--proof", "--public"],
+            _ => return Err(format!("Unknown command '{}'", command)),
+        };
 
-    // Create circuit
-    let circuit = ExecutionCircuit {
-        initial_state: vm.trace.first().unwrap().clone(),
-        final_state: vm.trace.last().unwrap().clone(),
-        program: program.clone(),
-        trace_commitment: vm.generate_trace_commitment("program.trace").expect("Failed to generate trace commitment"),
-    };
+        let mut options = Self {
+            command: command.clone(),
+            program: None,
+            trace: "program.trace".to_string(),
+            pk: "program.pk".to_string(),
+            vk: "program.vk".to_string(),
+            proof: "program.proof".to_string(),
+            public: "program.public".to_string(),
+            inputs: InputTapes::default(),
+            bounds: CircuitBounds::default(),
+            force: false,
+        };
+        while let Some((arg, tail)) = rest.split_first() {
+            rest = tail;
+            if !arg.starts_with("--") {
+                if options.program.is_some() || command == "setup" {
+                    return Err(format!("Unexpected argument '{}'", arg));
+                }
+                options.program = Some(arg.clone());
+                continue;
+            }
+            if !flags.contains(&arg.as_str()) {
+                return Err(format!("Unknown flag '{}' for {}", arg, command));
+            }
+            if arg == "--force" {
+                options.force = true;
+                continue;
+            }
+            let (value, tail) = rest.split_first().ok_or(format!("{} requires a value", arg))?;
+            rest = tail;
+            match arg.as_str() {
+                "--trace" => options.trace = value.clone(),
+                "--pk" => options.pk = value.clone(),
+                "--vk" => options.vk = value.clone(),
+                "--proof" => options.proof = value.clone(),
+                "--public" => options.public = value.clone(),
+                "--input" => options.inputs.public = parse_words(value)?,
+                "--secret" => options.inputs.secret = parse_words(value)?,
+                "--bounds" => options.bounds = parse_bounds(value)?,
+                _ => return Err(format!("Unknown flag '{}'", arg)),
+            }
+        }
+        if options.program.is_none() && matches!(command.as_str(), "run" | "prove") {
+            return Err(format!("{} requires a program", command));
+        }
+        Ok(options)
+    }
 
-    // Generate proving and verifying keys
-    let mut rng = ChaCha20Rng::from_entropy();
-    let (pk, _) = Groth16::<Bls12_381>::setup(circuit.clone(), &mut rng).unwrap();
+    fn program(&self) -> &str {
+        self.program.as_deref().unwrap_or_default()
+    }
+}
+
+fn load(path: &str) -> Result<Vec<Instruction>, String> {
+    load_program(path).map_err(|e| format!("Failed to load program '{}': {}", path, e))
+}
+
+fn parse_words(value: &str) -> Result<Vec<u32>, String> {
+    value
+        .split(',')
+        .map(|word| match word.trim() {
+            "" => Err(format!("Empty word in '{}'", value)),
+            word => word.parse().map_err(|e| format!("Invalid word '{}': {}", word, e)),
+        })
+        .collect()
+}
+
+fn parse_bounds(value: &str) -> Result<CircuitBounds, String> {
+    match parse_words(value)?.as_slice() {
+        &[max_steps, max_stack, max_program, max_call_depth, max_image, max_input, max_output] => Ok(CircuitBounds {
+            max_steps: max_steps as usize,
+            max_stack: max_stack as usize,
+            max_program: max_program as usize,
+            max_call_depth: max_call_depth as usize,
+            max_image: max_image as usize,
+            max_input: max_input as usize,
+            max_output: max_output as usize,
+        }),
+        words => Err(format!("--bounds takes 7 words, got {}", words.len())),
+    }
+}
+
+/// The public layout setup records in the keys. The layout, like the bounds, is
+/// part of the circuit, so it must not depend on the program.
+fn layout() -> PublicInputLayout {
+    PublicInputLayout {
+        initial_stack: true,
+        initial_heap: Vec::new(),
+        final_stack_top: true,
+        final_heap: (0..8).collect(),
+        final_pc: true,
+        final_gas: true,
+    }
+}
 
-    // Generate proof
-    vm.generate_proof(&program, "program.trace", proof_path, &pk).expect("Failed to generate proof");
+/// Runs the program and prints every state of its trace.
+fn run(options: &Options) -> Result<bool, Box<dyn Error>> {
+    let program = load(options.program())?;
+    let mut vm = ProvableVM::new();
+    vm.config.bounds = options.bounds;
+    let output = vm.run_program(&program, &options.inputs, &options.trace)?;
+    for (step, state) in vm.trace.iter().enumerate() {
+        println!(
+            "{:>5}  pc {:>4}  gas {:>6}  stack {:?}",
+            step, state.pc, state.gas_used, state.stack
+        );
+    }
+    println!("Output: {:?}", output);
+    println!("Trace commitment written to '{}'", options.trace);
+    Ok(true)
+}
 
-    // Load verifying key
-    let vk = load_vk(vk_path, &pk).expect("Failed to load verifying key");
+/// Sets up the keys from the blank circuit, which only depends on the bounds. Keys
+/// already there are only replaced with `--force`, since proofs made with them
+/// would no longer verify.
+fn setup_keys(options: &Options) -> Result<bool, Box<dyn Error>> {
+    for path in [&options.pk, &options.vk] {
+        if !options.force && Path::new(path).exists() {
+            return Err(format!("Key '{}' already exists, pass --force to replace it", path).into());
+        }
+    }
+    let mut rng = ChaCha20Rng::from_entropy();
+    let keys = generate_keys(options.bounds, &layout(), &mut rng)?;
+    save_keys(&options.pk, &options.vk, &keys.pk, &keys.shape)?;
+    println!("Proving key written to '{}', verifying key to '{}'", options.pk, options.vk);
+    Ok(true)
+}
 
-    // Prepare public inputs
-    let public_inputs: Vec<Fr> = vec![
-        convert_commitment_to_field(&circuit.trace_commitment),
-    ];
+/// Runs the program and proves the run with the proving key setup wrote, for the
+/// circuit recorded with it.
+fn prove(options: &Options) -> Result<bool, Box<dyn Error>> {
+    let program = load(options.program())?;
+    let mut vm = ProvableVM::new();
+    let output = vm.run_program(&program, &options.inputs, &options.trace)?;
+    println!("Output: {:?}", output);
 
-    // Verify proof
-    let valid = verify_proof(&vk, proof_path, &public_inputs);
+    if !Path::new(&options.pk).exists() {
+        return Err(format!("Proving key '{}' not found, run `setup` first", options.pk).into());
+    }
+    let pk = load_proving_key(&options.pk)?;
+    let public_inputs = vm.generate_proof(&program, &options.trace, &options.proof, &pk.key, &pk.shape)?;
+    println!("Proof written to '{}'", options.proof);
+    public_inputs.save(&options.public)?;
+    println!("Program hash: {}", hex::encode(&public_inputs.program_hash));
+    println!("Public inputs written to '{}'", options.public);
+    Ok(true)
+}
 
+/// Checks the proof against the public inputs, and against the program if one is
+/// given.
+fn verify(options: &Options) -> Result<bool, Box<dyn Error>> {
+    let vk = load_verifying_key(&options.vk)?;
+    let public_inputs = PublicInputs::load(&options.public)?;
+    println!("Program hash: {}", hex::encode(&public_inputs.program_hash));
+    println!("Output: {:?}", public_inputs.output);
+
+    let valid = match &options.program {
+        Some(path) => verify_program_proof(&vk.key, &vk.shape, &options.proof, &load(path)?, &public_inputs)?,
+        None => verify_proof(&vk.key, &vk.shape, &options.proof, &public_inputs)?,
+    };
     if valid {
         println!("Proof is valid!");
     } else {
         println!("Proof is invalid.");
     }
+    Ok(valid)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match options.command.as_str() {
+        "run" => run(&options),
+        "setup" => setup_keys(&options),
+        "prove" => prove(&options),
+        _ => verify(&options),
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
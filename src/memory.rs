@@ -0,0 +1,364 @@
+use ark_bls12_381::Fr;
+use ark_ff::One;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::R1CSVar;
+use ark_relations::lc;
+use ark_relations::r1cs::{ConstraintSystemRef, LinearCombination, SynthesisError, Variable};
+use crate::circuit::{pack, pack_lc, WORD_BITS};
+use crate::commitment;
+use crate::gadgets;
+use crate::vm::{Opcode, ProvableState};
+
+// Offline memory checking for the heap.
+//
+// The circuit does not carry the heap from state to state. Instead every step adds
+// one entry to a memory log: the cell it read or wrote, and when. The prover also
+// supplies the log sorted by address and time, where checking reads is local: a
+// read sees the cell exactly as the access before it at the same address left it,
+// and the first access to an address sees an absent cell. A grand-product check
+// with a challenge derived from the sorted log shows that both logs hold the same
+// accesses, so every LOAD returns the value last STOREd at its address.
+//
+// Time 0 holds the writes of the memory image and time 1 the reads of the public
+// initial cells. Step i accesses the heap at `step_time(i)`, and the public final
+// cells are read after the last step.
+
+/// Address of the log entries of steps that do not touch the heap. It lies above
+/// every word, so these entries sort after the real ones and never meet a read.
+pub const NULL_ADDRESS: u64 = 1 << WORD_BITS;
+
+/// Width of each field of a packed access. The address needs 33 bits for
+/// `NULL_ADDRESS`, the other fields are words or bits.
+const FIELD_BITS: usize = 34;
+
+/// Time of the memory log entry of step `i`.
+pub fn step_time(i: usize) -> u64 {
+    i as u64 + 2
+}
+
+/// One entry of the memory log: a read or a write of `(present, value)` at `address`
+/// at step `time`. Entries order by address, then time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Access {
+    pub address: u64,
+    pub time: u64,
+    pub value: u32,
+    pub present: bool,
+    pub write: bool,
+}
+
+impl Access {
+    /// The write of slot `j` of the memory image of a circuit with `steps` steps.
+    /// Slots past the end of the image write nothing at `NULL_ADDRESS`, each at its
+    /// own time after the last step.
+    pub fn image(j: usize, cell: Option<(u32, u32)>, steps: usize) -> Self {
+        match cell {
+            Some((address, value)) => Self {
+                address: address as u64,
+                time: 0,
+                value,
+                present: true,
+                write: true,
+            },
+            None => Self {
+                address: NULL_ADDRESS,
+                time: step_time(steps) + 1 + j as u64,
+                value: 0,
+                present: false,
+                write: true,
+            },
+        }
+    }
+
+    /// A read of the initial content of a public heap cell, before the first step.
+    pub fn initial(address: u32, cell: Option<u32>) -> Self {
+        Self {
+            address: address as u64,
+            time: 1,
+            value: cell.unwrap_or(0),
+            present: cell.is_some(),
+            write: false,
+        }
+    }
+
+    /// A read of the final content of a public heap cell, after the last of `steps`.
+    pub fn last(address: u32, cell: Option<u32>, steps: usize) -> Self {
+        Self {
+            address: address as u64,
+            time: step_time(steps),
+            value: cell.unwrap_or(0),
+            present: cell.is_some(),
+            write: false,
+        }
+    }
+
+    /// The access made by step `i`, which runs `opcode` with `operand` and moves the
+    /// machine from `state` to `next_state`. A load of a cell that was never stored
+    /// to reads it absent.
+    pub fn step(i: usize, opcode: Option<Opcode>, operand: u32, state: &ProvableState, next_state: &ProvableState) -> Self {
+        let top = |state: &ProvableState, k: usize| state.stack.iter().rev().nth(k).copied().unwrap_or(0);
+        let (address, value, write) = match opcode {
+            Some(Opcode::LOAD) => (operand, top(next_state, 0), false),
+            Some(Opcode::LOADI) => (top(state, 0), top(next_state, 0), false),
+            Some(Opcode::STORE) => (operand, top(state, 0), true),
+            Some(Opcode::STOREI) => (top(state, 0), top(state, 1), true),
+            _ => {
+                return Self {
+                    address: NULL_ADDRESS,
+                    time: step_time(i),
+                    value: 0,
+                    present: false,
+                    write: true,
+                }
+            }
+        };
+        Self {
+            address: address as u64,
+            time: step_time(i),
+            value,
+            present: write || state.heap.contains_key(&address),
+            write,
+        }
+    }
+
+    fn fields(&self) -> [u64; 5] {
+        [
+            self.address,
+            self.time,
+            self.value as u64,
+            self.present as u64,
+            self.write as u64,
+        ]
+    }
+
+    /// The access as a single field element, which is injective on in-range fields.
+    pub fn pack(&self) -> Fr {
+        pack(&self.fields(), FIELD_BITS)
+    }
+}
+
+/// Variables of a log entry, with the access they are assigned for.
+pub struct AccessVars {
+    pub address: LinearCombination<Fr>,
+    pub time: LinearCombination<Fr>,
+    pub value: LinearCombination<Fr>,
+    pub present: LinearCombination<Fr>,
+    pub write: LinearCombination<Fr>,
+    pub access: Access,
+}
+
+impl AccessVars {
+    /// An entry whose fields are all fixed by `access`, such as a padding step.
+    pub fn constant(access: Access) -> Self {
+        let constant = |value: u64| lc!() + (Fr::from(value), Variable::One);
+        let [address, time, value, present, write] = access.fields();
+        Self {
+            address: constant(address),
+            time: constant(time),
+            value: constant(value),
+            present: constant(present),
+            write: constant(write),
+            access,
+        }
+    }
+
+    fn pack(&self) -> LinearCombination<Fr> {
+        pack_lc(
+            [
+                self.address.clone(),
+                self.time.clone(),
+                self.value.clone(),
+                self.present.clone(),
+                self.write.clone(),
+            ],
+            FIELD_BITS,
+        )
+    }
+}
+
+/// Allocates the `slots` writes of the memory image `cells` before the first step
+/// of a circuit with `steps` steps, and returns them with the commitment to the
+/// image, see `commitment::image_commitment`.
+pub fn alloc_image(
+    cs: &ConstraintSystemRef<Fr>,
+    cells: &[(u32, u32)],
+    slots: usize,
+    steps: usize,
+) -> Result<(FpVar<Fr>, Vec<AccessVars>), SynthesisError> {
+    let mut writes = Vec::with_capacity(slots);
+    let mut entries = Vec::with_capacity(slots);
+    let mut mask = Vec::with_capacity(slots);
+    for j in 0..slots {
+        let cell = cells.get(j).copied();
+        let (address_value, value_value) = cell.unwrap_or_default();
+        let access = Access::image(j, cell, steps);
+
+        let used = cs.new_witness_variable(|| Ok(Fr::from(cell.is_some())))?;
+        gadgets::enforce_boolean(cs, used)?;
+        let address = cs.new_witness_variable(|| Ok(Fr::from(address_value)))?;
+        gadgets::to_bits(cs, address, address_value as u64, WORD_BITS)?;
+        let value = cs.new_witness_variable(|| Ok(Fr::from(value_value)))?;
+        gadgets::to_bits(cs, value, value_value as u64, WORD_BITS)?;
+
+        // An unused slot writes an absent cell at its own padding time and address
+        let padding = Access::image(j, None, steps);
+        let used_address = gadgets::product(cs, lc!() + used, lc!() + address, Fr::from(address_value))?;
+        let used_value = gadgets::product(cs, lc!() + used, lc!() + value, Fr::from(value_value))?;
+        writes.push(AccessVars {
+            address: lc!() + used_address + (Fr::from(padding.address), Variable::One) - (Fr::from(padding.address), used),
+            time: lc!() + (Fr::from(padding.time), Variable::One) - (Fr::from(padding.time), used),
+            value: lc!() + used_value,
+            present: lc!() + used,
+            write: lc!() + Variable::One,
+            access,
+        });
+
+        entries.push(cs.new_lc(pack_lc([lc!() + address, lc!() + value], WORD_BITS))?);
+        mask.push(used);
+    }
+    let commitment = commitment::image_commitment_gadget(cs, &entries, &mask)?;
+    Ok((commitment, writes))
+}
+
+/// Unless `zero_initialized` is set, `entry` must not read an absent cell.
+pub fn enforce_initialized(
+    cs: &ConstraintSystemRef<Fr>,
+    entry: &AccessVars,
+    zero_initialized: Variable,
+) -> Result<(), SynthesisError> {
+    let access = entry.access;
+    let missing = gadgets::product(
+        cs,
+        lc!() + Variable::One - &entry.write,
+        lc!() + Variable::One - &entry.present,
+        Fr::from(!access.write && !access.present),
+    )?;
+    cs.enforce_constraint(lc!() + missing, lc!() + Variable::One - zero_initialized, lc!())
+}
+
+/// Proves that `log` is a consistent history of the heap: every read returns what
+/// the last write to its address stored, or an absent cell if there was none.
+///
+/// Every field of a `log` entry must be range checked by the caller, so that packing
+/// is injective. The permutation challenge is derived from `seed` and the sorted
+/// log; `seed` must cover whatever fixes the unsorted log.
+pub fn enforce_consistency(cs: &ConstraintSystemRef<Fr>, seed: &[Variable], log: &[AccessVars]) -> Result<(), SynthesisError> {
+    let mut sorted_accesses: Vec<Access> = log.iter().map(|entry| entry.access).collect();
+    sorted_accesses.sort_unstable();
+
+    let mut sorted: Vec<AccessVars> = Vec::with_capacity(sorted_accesses.len());
+    for access in sorted_accesses {
+        let entry = alloc_sorted(cs, access)?;
+        if let Some(previous) = sorted.last() {
+            enforce_successor(cs, previous, &entry)?;
+        } else {
+            // The first access of all is also the first at its address
+            enforce_read(cs, &entry, lc!(), lc!())?;
+        }
+        sorted.push(entry);
+    }
+
+    let mut transcript: Vec<FpVar<Fr>> = seed.iter().map(|&var| gadgets::to_fp_var(cs, var)).collect();
+    for entry in &sorted {
+        transcript.push(gadgets::to_fp_var(cs, cs.new_lc(entry.pack())?));
+    }
+    let challenge = commitment::poseidon_hash_gadget(cs, &transcript)?;
+    // Unassigned while generating keys
+    let challenge_value = challenge.value().unwrap_or_default();
+    let challenge = gadgets::fp_var_lc(&challenge);
+
+    let unsorted_product = grand_product(cs, &challenge, challenge_value, log)?;
+    let sorted_product = grand_product(cs, &challenge, challenge_value, &sorted)?;
+    gadgets::enforce_equal(cs, unsorted_product, sorted_product)
+}
+
+/// Allocates and range checks an entry of the sorted log.
+fn alloc_sorted(cs: &ConstraintSystemRef<Fr>, access: Access) -> Result<AccessVars, SynthesisError> {
+    let mut fields = Vec::with_capacity(5);
+    for (value, bits) in access.fields().into_iter().zip([WORD_BITS + 1, WORD_BITS, WORD_BITS, 1, 1]) {
+        let var = cs.new_witness_variable(|| Ok(Fr::from(value)))?;
+        gadgets::to_bits(cs, var, value, bits)?;
+        fields.push(lc!() + var);
+    }
+    let [address, time, value, present, write]: [LinearCombination<Fr>; 5] =
+        fields.try_into().expect("an access has five fields");
+    Ok(AccessVars {
+        address,
+        time,
+        value,
+        present,
+        write,
+        access,
+    })
+}
+
+/// Constrains the sorted log entry following `previous`: it moves on to a higher
+/// address, or to a later time at the same one, and if it is a read it sees the cell
+/// as `previous` left it.
+fn enforce_successor(cs: &ConstraintSystemRef<Fr>, previous: &AccessVars, entry: &AccessVars) -> Result<(), SynthesisError> {
+    let (before, after) = (previous.access, entry.access);
+    let same_value = before.address == after.address;
+    let same = cs.new_witness_variable(|| Ok(Fr::from(same_value)))?;
+    gadgets::enforce_boolean(cs, same)?;
+    let address_step = entry.address.clone() - &previous.address;
+    let time_step = entry.time.clone() - &previous.time;
+    cs.enforce_constraint(lc!() + same, address_step.clone(), lc!())?;
+
+    // gap = address step - 1 for a new address, time step - 1 otherwise; both are
+    // positive exactly when the order is strict
+    let gap_value = if same_value {
+        after.time.wrapping_sub(before.time).wrapping_sub(1)
+    } else {
+        after.address.wrapping_sub(before.address).wrapping_sub(1)
+    };
+    let gap = cs.new_witness_variable(|| Ok(Fr::from(gap_value)))?;
+    gadgets::to_bits(cs, gap, gap_value, WORD_BITS + 1)?;
+    cs.enforce_constraint(
+        lc!() + same,
+        time_step - &address_step,
+        lc!() + gap - &address_step + Variable::One,
+    )?;
+
+    let carried_present = gadgets::product(
+        cs,
+        lc!() + same,
+        previous.present.clone(),
+        Fr::from(same_value && before.present),
+    )?;
+    let carried_value = gadgets::product(
+        cs,
+        lc!() + same,
+        previous.value.clone(),
+        Fr::from(if same_value { before.value } else { 0 }),
+    )?;
+    enforce_read(cs, entry, lc!() + carried_present, lc!() + carried_value)
+}
+
+/// Enforces that `entry`, if it is a read, sees the cell as `(present, value)`.
+fn enforce_read(
+    cs: &ConstraintSystemRef<Fr>,
+    entry: &AccessVars,
+    present: LinearCombination<Fr>,
+    value: LinearCombination<Fr>,
+) -> Result<(), SynthesisError> {
+    let reads = lc!() + Variable::One - &entry.write;
+    gadgets::enforce_equal_if(cs, reads.clone(), entry.present.clone(), present)?;
+    gadgets::enforce_equal_if(cs, reads, entry.value.clone(), value)
+}
+
+/// `prod (challenge - packed entry)` over `log`.
+fn grand_product(
+    cs: &ConstraintSystemRef<Fr>,
+    challenge: &LinearCombination<Fr>,
+    challenge_value: Fr,
+    log: &[AccessVars],
+) -> Result<LinearCombination<Fr>, SynthesisError> {
+    let mut product = lc!() + Variable::One;
+    let mut product_value = Fr::one();
+    for entry in log {
+        product_value *= challenge_value - entry.access.pack();
+        let next = gadgets::product(cs, product, challenge.clone() - &entry.pack(), product_value)?;
+        product = lc!() + next;
+    }
+    Ok(product)
+}
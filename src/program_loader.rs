@@ -1,7 +1,16 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use crate::commitment::{commitment_to_bytes, program_commitment};
 use crate::vm::{Instruction, Opcode};
 
+/// Canonical hash of a program: a Poseidon chain over the encoding of every
+/// instruction, see `commitment::program_commitment`. Proofs expose it as a
+/// public input.
+pub fn program_hash(program: &[Instruction]) -> Vec<u8> {
+    let encoded: Vec<u64> = program.iter().map(Instruction::encode).collect();
+    commitment_to_bytes(program_commitment(&encoded))
+}
+
 pub fn load_program(file_path: &str) -> Result<Vec<Instruction>, String> {
     let file = File::open(file_path).map_err(|e| e.to_string())?;
     let reader = BufReader::new(file);
@@ -25,10 +34,38 @@ pub fn load_program(file_path: &str) -> Result<Vec<Instruction>, String> {
                         "POP" => Some(Opcode::POP),
                         "ADD" => Some(Opcode::ADD),
                         "SUB" => Some(Opcode::SUB),
+                        "MUL" => Some(Opcode::MUL),
+                        "DIVU" => Some(Opcode::DIVU),
+                        "MODU" => Some(Opcode::MODU),
+                        "AND" => Some(Opcode::AND),
+                        "OR" => Some(Opcode::OR),
+                        "XOR" => Some(Opcode::XOR),
+                        "NOT" => Some(Opcode::NOT),
+                        "SHL" => Some(Opcode::SHL),
+                        "SHR" => Some(Opcode::SHR),
+                        "EQ" => Some(Opcode::EQ),
+                        "LT" => Some(Opcode::LT),
+                        "GT" => Some(Opcode::GT),
+                        "SLT" => Some(Opcode::SLT),
+                        "SGT" => Some(Opcode::SGT),
+                        "JNZ" => Some(Opcode::JNZ),
+                        "JLT" => Some(Opcode::JLT),
+                        "JEQ" => Some(Opcode::JEQ),
+                        "DUP" => Some(Opcode::DUP),
+                        "SWAP" => Some(Opcode::SWAP),
+                        "OVER" => Some(Opcode::OVER),
+                        "ROT" => Some(Opcode::ROT),
+                        "CALL" => Some(Opcode::CALL),
+                        "RET" => Some(Opcode::RET),
                         "JMP" => Some(Opcode::JMP),
                         "JZ" => Some(Opcode::JZ),
                         "LOAD" => Some(Opcode::LOAD),
                         "STORE" => Some(Opcode::STORE),
+                        "LOADI" => Some(Opcode::LOADI),
+                        "STOREI" => Some(Opcode::STOREI),
+                        "READ" => Some(Opcode::READ),
+                        "READS" => Some(Opcode::READS),
+                        "WRITE" => Some(Opcode::WRITE),
                         "HALT" => Some(Opcode::HALT),
                         _ => return Some(Err(format!("Unknown opcode: {}", parts[0]))),
                     };
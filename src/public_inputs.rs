@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use ark_bls12_381::Fr;
+use std::fs::File;
+use std::io;
+use crate::circuit::CircuitBounds;
+use crate::commitment::{commitment_to_bytes, image_commitment, input_commitment};
+use crate::program_loader::program_hash;
+use crate::utils::convert_commitment_to_field;
+use crate::vm::{Instruction, MemoryModel, Opcode, ProvableState};
+
+/// Selects which parts of an execution are exposed to the verifier.
+///
+/// The trace commitment, the program hash, the memory model (whether the heap is
+/// zero-initialised, then the commitment to its image), the commitment to the
+/// public input tape and the output always come first; the enabled entries follow
+/// in field declaration order. The output is exposed as its length followed by
+/// every word, padded with zeros to `max_output`. The initial
+/// stack is exposed as its length followed by every slot, top-first. Heap cells
+/// are listed by address, at most once each, and are exposed as a
+/// `(present, value)` pair. So is the final stack top, which is absent when the
+/// stack ends up empty. Padding to the bounds is up to the verifier, who knows the
+/// circuit the proof is checked against.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicInputLayout {
+    pub initial_stack: bool,
+    pub initial_heap: Vec<u32>,
+    pub final_stack_top: bool,
+    pub final_heap: Vec<u32>,
+    pub final_pc: bool,
+    pub final_gas: bool,
+}
+
+/// The values a proof is verified against, as laid out by a `PublicInputLayout`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicInputs {
+    pub trace_commitment: Vec<u8>,
+    pub program_hash: Vec<u8>,
+    pub zero_initialized: bool,
+    pub memory_image: Vec<u8>,
+    pub input_tape: Vec<u8>,
+    /// Words the program wrote to the output tape.
+    pub output: Vec<u32>,
+    pub initial_stack: Option<Vec<u32>>,
+    pub initial_heap: Vec<(u32, Option<u32>)>,
+    /// The top of the final stack if it is exposed, `None` inside for an empty stack.
+    pub final_stack_top: Option<Option<u32>>,
+    pub final_heap: Vec<(u32, Option<u32>)>,
+    pub final_pc: Option<u32>,
+    pub final_gas: Option<u64>,
+}
+
+impl PublicInputs {
+    /// Writes the public inputs to `file_path`, for a verifier to check a proof against.
+    pub fn save(&self, file_path: &str) -> io::Result<()> {
+        let file = File::create(file_path)?;
+        bincode::serialize_into(file, self).map_err(io::Error::other)
+    }
+
+    /// Reads public inputs written by `save`.
+    pub fn load(file_path: &str) -> io::Result<Self> {
+        let file = File::open(file_path)?;
+        bincode::deserialize_from(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reads the exposed values off an execution trace.
+    pub fn from_trace(
+        layout: &PublicInputLayout,
+        program: &[Instruction],
+        memory: &MemoryModel,
+        public_tape: &[u32],
+        trace: &[ProvableState],
+        trace_commitment: &[u8],
+    ) -> Self {
+        let initial = trace.first();
+        let last = trace.last();
+        let heap_cells = |state: Option<&ProvableState>, addresses: &[u32]| {
+            addresses
+                .iter()
+                .map(|&addr| (addr, state.and_then(|s| s.heap.get(&addr).copied())))
+                .collect()
+        };
+
+        Self {
+            trace_commitment: trace_commitment.to_vec(),
+            program_hash: program_hash(program),
+            zero_initialized: memory.zero_initialized(),
+            memory_image: commitment_to_bytes(image_commitment(&memory.image())),
+            input_tape: commitment_to_bytes(input_commitment(public_tape)),
+            output: output(program, trace),
+            initial_stack: layout
+                .initial_stack
+                .then(|| initial.map(|s| s.stack.clone()).unwrap_or_default()),
+            initial_heap: heap_cells(initial, &layout.initial_heap),
+            final_stack_top: layout
+                .final_stack_top
+                .then(|| last.and_then(|s| s.stack.last().copied())),
+            final_heap: heap_cells(last, &layout.final_heap),
+            final_pc: layout.final_pc.then(|| last.map(|s| s.pc).unwrap_or(0)),
+            final_gas: layout.final_gas.then(|| last.map(|s| s.gas_used).unwrap_or(0)),
+        }
+    }
+
+    /// Field elements in the order the circuit with `bounds` allocates its public
+    /// inputs.
+    pub fn to_field_elements(&self, bounds: &CircuitBounds) -> Vec<Fr> {
+        let mut elements = vec![
+            convert_commitment_to_field(&self.trace_commitment),
+            convert_commitment_to_field(&self.program_hash),
+            Fr::from(self.zero_initialized),
+            convert_commitment_to_field(&self.memory_image),
+            convert_commitment_to_field(&self.input_tape),
+            Fr::from(self.output.len() as u64),
+        ];
+        let mut output = self.output.clone();
+        output.resize(bounds.max_output.max(output.len()), 0);
+        elements.extend(output.into_iter().map(Fr::from));
+        if let Some(stack) = &self.initial_stack {
+            let mut slots: Vec<u32> = stack.iter().rev().copied().collect();
+            slots.resize(bounds.max_stack.max(slots.len()), 0);
+            elements.push(Fr::from(stack.len() as u64));
+            elements.extend(slots.into_iter().map(Fr::from));
+        }
+        elements.extend(heap_cell_elements(&self.initial_heap));
+        if let Some(top) = self.final_stack_top {
+            elements.extend([Fr::from(top.is_some()), Fr::from(top.unwrap_or(0))]);
+        }
+        elements.extend(heap_cell_elements(&self.final_heap));
+        elements.extend(self.final_pc.map(Fr::from));
+        elements.extend(self.final_gas.map(Fr::from));
+        elements
+    }
+}
+
+/// Words written by the WRITE steps of `trace`, in order.
+fn output(program: &[Instruction], trace: &[ProvableState]) -> Vec<u32> {
+    trace
+        .windows(2)
+        .filter(|step| {
+            program
+                .get(step[0].pc as usize)
+                .is_some_and(|instruction| instruction.opcode == Opcode::WRITE)
+        })
+        .map(|step| step[0].stack.last().copied().unwrap_or(0))
+        .collect()
+}
+
+fn heap_cell_elements(cells: &[(u32, Option<u32>)]) -> impl Iterator<Item = Fr> + '_ {
+    cells
+        .iter()
+        .flat_map(|&(_, value)| [Fr::from(value.is_some()), Fr::from(value.unwrap_or(0))])
+}
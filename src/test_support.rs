@@ -0,0 +1,23 @@
+//! Helpers shared by the unit tests.
+
+use crate::circuit::CircuitBounds;
+use crate::vm::{Instruction, Opcode};
+
+/// Bounds small enough to set up keys quickly, large enough for the test programs.
+pub const BOUNDS: CircuitBounds = CircuitBounds {
+    max_steps: 16,
+    max_stack: 4,
+    max_program: 8,
+    max_call_depth: 2,
+    max_image: 1,
+    max_input: 2,
+    max_output: 2,
+};
+
+pub fn instruction(opcode: Opcode, operand: Option<u32>) -> Instruction {
+    Instruction { opcode, operand }
+}
+
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(name).to_str().unwrap().to_string()
+}
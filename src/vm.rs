@@ -1,22 +1,98 @@
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Write};
-use ark_groth16::{ProvingKey};
 use ark_bls12_381::{Bls12_381, Fr};
-use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError, Variable};
-use ark_relations::lc;
+use ark_groth16::ProvingKey;
 use ark_std::vec::Vec;
-use crate::utils::convert_commitment_to_field;
-use crate::zk_proof;
+use ark_std::UniformRand;
+use rand_chacha::ChaCha20Rng;
+use rand_core::SeedableRng;
+use crate::circuit::CircuitBounds;
+use crate::commitment;
+use crate::circuit::CircuitShape;
+use crate::public_inputs::PublicInputs;
+use crate::zk_proof::{self, ProverError};
+
+/// Bit of `ProvableState::flags` holding the carry of the last ADD, SUB or MUL.
+///
+/// Words are unsigned 32-bit integers and arithmetic wraps around modulo 2^32:
+/// ADD sets the carry when the sum overflows, SUB when the subtraction borrows and
+/// MUL when the product does not fit in a word. Every other instruction leaves the
+/// flags alone.
+pub const CARRY_FLAG: u32 = 0;
+
+/// Bits of `ProvableState::flags` set by the comparisons EQ, LT, GT, SLT and SGT:
+/// whether the two operands were equal and whether the second one was less than
+/// the top one. LT, GT and EQ compare unsigned words, SLT and SGT two's complement
+/// ones. JLT jumps on the less flag and JEQ on the equal flag.
+pub const EQUAL_FLAG: u32 = 1;
+pub const LESS_FLAG: u32 = 2;
+
+/// Default bound on the number of return addresses CALL may push, see
+/// `ProvableVM::max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 64;
+
+/// What the heap holds before the program stores anything to it.
+///
+/// The model is part of the public inputs of a proof: whether loads of cells that
+/// were never stored to read zero, and a commitment to the image.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MemoryModel {
+    /// The heap starts out empty and loading a cell that was never stored to fails.
+    #[default]
+    Strict,
+    /// The heap starts out empty and every cell that was never stored to reads as zero.
+    Zeroed,
+    /// The heap starts out holding the image, every other cell reads as zero.
+    Image(BTreeMap<u32, u32>),
+}
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl MemoryModel {
+    /// Whether loading a cell that was never stored to reads zero instead of failing.
+    pub fn zero_initialized(&self) -> bool {
+        !matches!(self, MemoryModel::Strict)
+    }
+
+    /// Cells the heap starts out with, by ascending address.
+    pub fn image(&self) -> Vec<(u32, u32)> {
+        match self {
+            MemoryModel::Image(image) => image.iter().map(|(&address, &value)| (address, value)).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Words a program receives: READ consumes the public tape and READS the secret
+/// one, each from the front.
+///
+/// A proof commits to the public tape as a public input and keeps the secret tape
+/// in the witness. The only public value computed from it is the trace commitment,
+/// which is blinded (see `ProvableVM::trace_blinding`), so the verifier cannot test
+/// guesses of the secret tape against it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputTapes {
+    pub public: Vec<u32>,
+    pub secret: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ProvableState {
     pub pc: u32,
     pub stack: Vec<u32>,
     pub heap: HashMap<u32, u32>,
     pub flags: u8,
+    /// Return addresses pushed by CALL, innermost last.
+    pub call_stack: Vec<u32>,
+    /// Words read so far from the public and the secret input tape.
+    pub public_read: u32,
+    pub secret_read: u32,
+    /// Words written so far to the output tape.
+    pub written: u32,
+    /// Gas used by the instructions run so far, see `GasCosts`.
+    pub gas_used: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -25,7 +101,17 @@ pub struct Instruction {
     pub operand: Option<u32>,
 }
 
+impl Instruction {
+    /// Packs the instruction into a single integer: the opcode in the low 8 bits,
+    /// a bit telling whether an operand is present, then the operand (or zero).
+    /// This is the form programs are committed to and fetched in-circuit.
+    pub fn encode(&self) -> u64 {
+        self.opcode as u64 | (self.operand.is_some() as u64) << 8 | (self.operand.unwrap_or(0) as u64) << 9
+    }
+}
+
 #[repr(u32)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Opcode {
     PUSH = 1,
@@ -37,24 +123,287 @@ pub enum Opcode {
     LOAD = 7,
     STORE = 8,
     HALT = 9,
+    MUL = 10,
+    DIVU = 11,
+    MODU = 12,
+    AND = 13,
+    OR = 14,
+    XOR = 15,
+    NOT = 16,
+    SHL = 17,
+    SHR = 18,
+    EQ = 19,
+    LT = 20,
+    GT = 21,
+    SLT = 22,
+    SGT = 23,
+    JNZ = 24,
+    JLT = 25,
+    DUP = 26,
+    SWAP = 27,
+    OVER = 28,
+    ROT = 29,
+    CALL = 30,
+    RET = 31,
+    LOADI = 32,
+    STOREI = 33,
+    READ = 34,
+    READS = 35,
+    WRITE = 36,
+    JEQ = 37,
+}
+
+impl Opcode {
+    /// Position of the opcode in `Opcode::ALL`.
+    pub fn index(self) -> usize {
+        Opcode::ALL.iter().position(|&op| op == self).expect("Opcode missing from Opcode::ALL")
+    }
+
+    pub const ALL: [Opcode; 37] = [
+        Opcode::PUSH,
+        Opcode::POP,
+        Opcode::ADD,
+        Opcode::SUB,
+        Opcode::JMP,
+        Opcode::JZ,
+        Opcode::LOAD,
+        Opcode::STORE,
+        Opcode::HALT,
+        Opcode::MUL,
+        Opcode::DIVU,
+        Opcode::MODU,
+        Opcode::AND,
+        Opcode::OR,
+        Opcode::XOR,
+        Opcode::NOT,
+        Opcode::SHL,
+        Opcode::SHR,
+        Opcode::EQ,
+        Opcode::LT,
+        Opcode::GT,
+        Opcode::SLT,
+        Opcode::SGT,
+        Opcode::JNZ,
+        Opcode::JLT,
+        Opcode::DUP,
+        Opcode::SWAP,
+        Opcode::OVER,
+        Opcode::ROT,
+        Opcode::CALL,
+        Opcode::RET,
+        Opcode::LOADI,
+        Opcode::STOREI,
+        Opcode::READ,
+        Opcode::READS,
+        Opcode::WRITE,
+        Opcode::JEQ,
+    ];
+}
+
+/// Gas charged for running each opcode, indexed like `Opcode::ALL`.
+///
+/// HALT is always free: a halted machine keeps running it until the trace is
+/// padded out, without using any more gas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GasCosts(pub [u64; Opcode::ALL.len()]);
+
+impl GasCosts {
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        if opcode == Opcode::HALT {
+            0
+        } else {
+            self.0[opcode.index()]
+        }
+    }
+
+    pub fn set(&mut self, opcode: Opcode, cost: u64) {
+        self.0[opcode.index()] = cost;
+    }
+}
+
+/// Serialized as a sequence, since serde only derives arrays of up to 32 elements.
+impl Serialize for GasCosts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.as_slice().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for GasCosts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let costs = Vec::<u64>::deserialize(deserializer)?;
+        let len = costs.len();
+        costs
+            .try_into()
+            .map(GasCosts)
+            .map_err(|_| de::Error::invalid_length(len, &"a cost for every opcode"))
+    }
 }
 
+impl Default for GasCosts {
+    /// One unit per instruction, two for multiplication, division and accesses to
+    /// the heap or the call stack.
+    fn default() -> Self {
+        let mut costs = GasCosts([1; Opcode::ALL.len()]);
+        for opcode in [
+            Opcode::MUL,
+            Opcode::DIVU,
+            Opcode::MODU,
+            Opcode::LOAD,
+            Opcode::STORE,
+            Opcode::LOADI,
+            Opcode::STOREI,
+            Opcode::CALL,
+            Opcode::RET,
+        ] {
+            costs.set(opcode, 2);
+        }
+        costs
+    }
+}
+
+/// Limits of `ProvableVM::run_program`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunConfig {
+    /// Gas available to the whole run.
+    pub gas_limit: u64,
+    /// Instructions the run may execute, HALT included.
+    pub max_steps: usize,
+    pub costs: GasCosts,
+    /// Bounds of the circuit the trace commitment written after the run is padded for.
+    pub bounds: CircuitBounds,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            gas_limit: 1_000_000,
+            max_steps: 100_000,
+            costs: GasCosts::default(),
+            bounds: CircuitBounds::default(),
+        }
+    }
+}
+
+/// Why `ProvableVM::run_program` stopped before the program halted. Faults of
+/// an instruction carry the pc it ran at and its opcode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// The instruction needs `needed` elements on the stack but only `found` are there.
+    StackUnderflow { pc: u32, opcode: Opcode, needed: usize, found: usize },
+    /// The instruction takes an operand but has none.
+    MissingOperand { pc: u32, opcode: Opcode },
+    /// The operand is out of range for the instruction, like SWAP 0.
+    InvalidOperand { pc: u32, opcode: Opcode, operand: u32 },
+    /// A load of a cell that was never stored to, under `MemoryModel::Strict`.
+    UnknownAddress { pc: u32, opcode: Opcode, address: u32 },
+    /// DIVU or MODU by zero.
+    DivisionByZero { pc: u32, opcode: Opcode },
+    /// A jump or call to a target outside the program.
+    InvalidJump { pc: u32, opcode: Opcode, target: u32, program_len: usize },
+    /// CALL with `max_depth` return addresses already on the call stack.
+    CallDepthExceeded { pc: u32, max_depth: usize },
+    /// RET with an empty call stack.
+    CallStackUnderflow { pc: u32 },
+    /// READ or READS past the end of its input tape.
+    InputExhausted { pc: u32, opcode: Opcode },
+    /// The instruction at `pc` needs more gas than is left of `limit`.
+    OutOfGas { pc: u32, limit: u64 },
+    /// The program ran `max_steps` instructions without halting.
+    StepLimit { max_steps: usize },
+    /// The trace commitment could not be written to `path`.
+    TraceFile { path: String, message: String },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { pc, opcode, needed, found } => write!(
+                f,
+                "{:?} at pc {} requires {} elements on the stack, found {}",
+                opcode, pc, needed, found
+            ),
+            VmError::MissingOperand { pc, opcode } => write!(f, "{:?} at pc {} requires an operand", opcode, pc),
+            VmError::InvalidOperand { pc, opcode, operand } => {
+                write!(f, "{:?} at pc {} has an invalid operand {}", opcode, pc, operand)
+            }
+            VmError::UnknownAddress { pc, opcode, address } => {
+                write!(f, "{:?} at pc {} failed: address {} not found", opcode, pc, address)
+            }
+            VmError::DivisionByZero { pc, opcode } => write!(f, "{:?} at pc {} by zero", opcode, pc),
+            VmError::InvalidJump { pc, opcode, target, program_len } => write!(
+                f,
+                "{:?} at pc {} targets {}, out of range (program has {} instructions)",
+                opcode, pc, target, program_len
+            ),
+            VmError::CallDepthExceeded { pc, max_depth } => {
+                write!(f, "CALL at pc {} exceeds the maximum call depth of {}", pc, max_depth)
+            }
+            VmError::CallStackUnderflow { pc } => write!(f, "RET at pc {} with an empty call stack", pc),
+            VmError::InputExhausted { pc, opcode } => {
+                write!(f, "{:?} at pc {} past the end of its input tape", opcode, pc)
+            }
+            VmError::OutOfGas { pc, limit } => write!(f, "Out of gas at pc {} (limit {})", pc, limit),
+            VmError::StepLimit { max_steps } => write!(f, "Program did not halt within {} steps", max_steps),
+            VmError::TraceFile { path, message } => write!(f, "Failed to write trace to '{}': {}", path, message),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
 pub struct ProvableVM {
     pub pc: u32,
     pub stack: Vec<u32>,
     pub heap: HashMap<u32, u32>,
     pub flags: u8,
+    pub call_stack: Vec<u32>,
+    /// How the heap is initialised, see `MemoryModel`.
+    pub memory: MemoryModel,
+    /// Input tapes of the last `run_program`, and how far READ and READS got.
+    pub inputs: InputTapes,
+    pub public_read: u32,
+    pub secret_read: u32,
+    /// Words the program emitted with WRITE, which proofs expose as public outputs.
+    pub output: Vec<u32>,
+    pub gas_used: u64,
+    pub config: RunConfig,
+    /// CALL fails once this many return addresses are on the call stack.
+    pub max_call_depth: usize,
     pub trace: Vec<ProvableState>,
+    /// Random field element the trace commitment is blinded with, drawn when the
+    /// VM is created. Proofs keep it in the witness.
+    pub trace_blinding: Fr,
+}
+
+impl Default for ProvableVM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProvableVM {
     pub fn new() -> Self {
+        Self::with_memory(MemoryModel::default())
+    }
+
+    /// A VM whose heap is initialised by `memory`.
+    pub fn with_memory(memory: MemoryModel) -> Self {
         Self {
             pc: 0,
             stack: Vec::new(),
-            heap: HashMap::new(),
+            heap: memory.image().into_iter().collect(),
             flags: 0,
+            call_stack: Vec::new(),
+            memory,
+            inputs: InputTapes::default(),
+            public_read: 0,
+            secret_read: 0,
+            output: Vec::new(),
+            gas_used: 0,
+            config: RunConfig::default(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
             trace: Vec::new(),
+            trace_blinding: Fr::rand(&mut ChaCha20Rng::from_entropy()),
         }
     }
 
@@ -64,8 +413,9 @@ impl ProvableVM {
         trace_file: &str,
         proof_file: &str,
         pk: &ProvingKey<Bls12_381>,
-    ) -> std::io::Result<()> {
-        zk_proof::generate_proof(self, program, trace_file, proof_file, pk)
+        shape: &CircuitShape,
+    ) -> Result<PublicInputs, ProverError> {
+        zk_proof::generate_proof(self, program, trace_file, proof_file, pk, shape)
     }
 
     fn capture_state(&self) -> ProvableState {
@@ -74,296 +424,478 @@ impl ProvableVM {
             stack: self.stack.clone(),
             heap: self.heap.clone(),
             flags: self.flags,
+            call_stack: self.call_stack.clone(),
+            public_read: self.public_read,
+            secret_read: self.secret_read,
+            written: self.output.len() as u32,
+            gas_used: self.gas_used,
+        }
+    }
+
+    fn set_flag(&mut self, bit: u32, value: bool) {
+        self.flags = (self.flags & !(1 << bit)) | ((value as u8) << bit);
+    }
+
+    /// The value `depth` elements below the top of the stack.
+    fn stack_value(&self, depth: u32) -> Option<u32> {
+        self.stack.iter().rev().nth(depth as usize).copied()
+    }
+
+    /// The top `N` elements of the stack, top first.
+    fn peek<const N: usize>(&self, opcode: Opcode) -> Result<[u32; N], VmError> {
+        if self.stack.len() < N {
+            return Err(VmError::StackUnderflow {
+                pc: self.pc,
+                opcode,
+                needed: N,
+                found: self.stack.len(),
+            });
+        }
+        Ok(std::array::from_fn(|i| self.stack[self.stack.len() - 1 - i]))
+    }
+
+    /// Pops the top `N` elements off the stack, top first. Leaves the stack alone
+    /// if it holds fewer.
+    fn pop<const N: usize>(&mut self, opcode: Opcode) -> Result<[u32; N], VmError> {
+        let values = self.peek(opcode)?;
+        self.stack.truncate(self.stack.len() - N);
+        Ok(values)
+    }
+
+    fn operand(&self, instruction: &Instruction) -> Result<u32, VmError> {
+        instruction.operand.ok_or(VmError::MissingOperand {
+            pc: self.pc,
+            opcode: instruction.opcode,
+        })
+    }
+
+    /// The value at `addr`, for `opcode` loading it.
+    fn load(&self, opcode: Opcode, addr: u32) -> Result<u32, VmError> {
+        match self.heap.get(&addr) {
+            Some(&value) => Ok(value),
+            None if self.memory.zero_initialized() => Ok(0),
+            None => Err(VmError::UnknownAddress {
+                pc: self.pc,
+                opcode,
+                address: addr,
+            }),
         }
     }
 
-    fn execute_instruction(&mut self, instruction: &Instruction) -> Result<bool, String> {
-        match instruction.opcode {
+    /// The next word of the input tape READ or READS consumes.
+    fn read(&mut self, opcode: Opcode) -> Result<u32, VmError> {
+        let (tape, read) = if opcode == Opcode::READ {
+            (&self.inputs.public, &mut self.public_read)
+        } else {
+            (&self.inputs.secret, &mut self.secret_read)
+        };
+        let value = *tape
+            .get(*read as usize)
+            .ok_or(VmError::InputExhausted { pc: self.pc, opcode })?;
+        *read += 1;
+        Ok(value)
+    }
+
+    fn jump_target(&self, instruction: &Instruction, program_len: usize) -> Result<u32, VmError> {
+        let target = self.operand(instruction)?;
+        if target as usize >= program_len {
+            return Err(VmError::InvalidJump {
+                pc: self.pc,
+                opcode: instruction.opcode,
+                target,
+                program_len,
+            });
+        }
+        Ok(target)
+    }
+
+    fn execute_instruction(&mut self, instruction: &Instruction, program_len: usize) -> Result<bool, VmError> {
+        let opcode = instruction.opcode;
+        match opcode {
             Opcode::PUSH => {
-                if let Some(value) = instruction.operand {
-                    self.stack.push(value);
-                } else {
-                    return Err("PUSH requires an operand".to_string());
-                }
+                let value = self.operand(instruction)?;
+                self.stack.push(value);
             }
             Opcode::POP => {
-                self.stack.pop().ok_or("POP requires at least one element on the stack".to_string())?;
+                self.pop::<1>(opcode)?;
             }
             Opcode::ADD => {
-                let a = self.stack.pop().ok_or("ADD requires two elements on the stack".to_string())?;
-                let b = self.stack.pop().ok_or("ADD requires two elements on the stack".to_string())?;
-                self.stack.push(a + b);
+                let [a, b] = self.pop(opcode)?;
+                let (sum, carry) = b.overflowing_add(a);
+                self.stack.push(sum);
+                self.set_flag(CARRY_FLAG, carry);
             }
             Opcode::SUB => {
-                let a = self.stack.pop().ok_or("SUB requires two elements on the stack".to_string())?;
-                let b = self.stack.pop().ok_or("SUB requires two elements on the stack".to_string())?;
-                self.stack.push(b.checked_sub(a).ok_or("SUB resulted in an underflow".to_string())?);
+                let [a, b] = self.pop(opcode)?;
+                let (difference, borrow) = b.overflowing_sub(a);
+                self.stack.push(difference);
+                self.set_flag(CARRY_FLAG, borrow);
+            }
+            Opcode::MUL => {
+                let [a, b] = self.pop(opcode)?;
+                let (product, carry) = b.overflowing_mul(a);
+                self.stack.push(product);
+                self.set_flag(CARRY_FLAG, carry);
+            }
+            Opcode::DIVU | Opcode::MODU => {
+                let [a, b] = self.peek(opcode)?;
+                if a == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.pc, opcode });
+                }
+                self.pop::<2>(opcode)?;
+                self.stack.push(if opcode == Opcode::DIVU { b / a } else { b % a });
+            }
+            Opcode::AND | Opcode::OR | Opcode::XOR | Opcode::SHL | Opcode::SHR => {
+                let [a, b] = self.pop(opcode)?;
+                // Shift amounts are taken modulo the word size
+                self.stack.push(match opcode {
+                    Opcode::AND => b & a,
+                    Opcode::OR => b | a,
+                    Opcode::XOR => b ^ a,
+                    Opcode::SHL => b.wrapping_shl(a),
+                    _ => b.wrapping_shr(a),
+                });
+            }
+            Opcode::NOT => {
+                let [a] = self.pop(opcode)?;
+                self.stack.push(!a);
+            }
+            Opcode::EQ | Opcode::LT | Opcode::GT | Opcode::SLT | Opcode::SGT => {
+                let [a, b] = self.pop(opcode)?;
+                let ordering = match opcode {
+                    Opcode::SLT | Opcode::SGT => (b as i32).cmp(&(a as i32)),
+                    _ => b.cmp(&a),
+                };
+                let result = match opcode {
+                    Opcode::EQ => ordering == Ordering::Equal,
+                    Opcode::LT | Opcode::SLT => ordering == Ordering::Less,
+                    _ => ordering == Ordering::Greater,
+                };
+                self.stack.push(result as u32);
+                self.set_flag(EQUAL_FLAG, ordering == Ordering::Equal);
+                self.set_flag(LESS_FLAG, ordering == Ordering::Less);
+            }
+            Opcode::DUP | Opcode::SWAP => {
+                let depth = self.operand(instruction)?;
+                if opcode == Opcode::SWAP && depth == 0 {
+                    return Err(VmError::InvalidOperand {
+                        pc: self.pc,
+                        opcode,
+                        operand: depth,
+                    });
+                }
+                let value = self.stack_value(depth).ok_or(VmError::StackUnderflow {
+                    pc: self.pc,
+                    opcode,
+                    needed: depth as usize + 1,
+                    found: self.stack.len(),
+                })?;
+                let top = self.stack.len() - 1;
+                if opcode == Opcode::DUP {
+                    self.stack.push(value);
+                } else {
+                    self.stack.swap(top, top - depth as usize);
+                }
+            }
+            Opcode::OVER => {
+                let [a, b] = self.pop(opcode)?;
+                self.stack.extend([b, a, b]);
+            }
+            Opcode::ROT => {
+                let [a, b, c] = self.pop(opcode)?;
+                self.stack.extend([b, a, c]);
             }
             Opcode::LOAD => {
-                let addr = instruction.operand.ok_or("LOAD requires an address operand".to_string())?;
-                let value = *self.heap.get(&addr).ok_or(format!("LOAD failed: address {} not found", addr))?;
+                let addr = self.operand(instruction)?;
+                let value = self.load(opcode, addr)?;
                 self.stack.push(value);
             }
             Opcode::STORE => {
-                let addr = instruction.operand.ok_or("STORE requires an address operand".to_string())?;
-                let value = self.stack.pop().ok_or("STORE requires a value on the stack".to_string())?;
+                let addr = self.operand(instruction)?;
+                let [value] = self.pop(opcode)?;
                 self.heap.insert(addr, value);
             }
+            Opcode::LOADI => {
+                let [addr] = self.peek(opcode)?;
+                let value = self.load(opcode, addr)?;
+                self.pop::<1>(opcode)?;
+                self.stack.push(value);
+            }
+            Opcode::STOREI => {
+                let [addr, value] = self.pop(opcode)?;
+                self.heap.insert(addr, value);
+            }
+            Opcode::READ | Opcode::READS => {
+                let value = self.read(opcode)?;
+                self.stack.push(value);
+            }
+            Opcode::WRITE => {
+                let [value] = self.pop(opcode)?;
+                self.output.push(value);
+            }
+            Opcode::JMP => {
+                self.pc = self.jump_target(instruction, program_len)?;
+                return Ok(true);
+            }
+            Opcode::JZ | Opcode::JNZ => {
+                let target = self.jump_target(instruction, program_len)?;
+                let [value] = self.pop(opcode)?;
+                if (value == 0) == (opcode == Opcode::JZ) {
+                    self.pc = target;
+                    return Ok(true);
+                }
+            }
+            Opcode::JLT | Opcode::JEQ => {
+                let target = self.jump_target(instruction, program_len)?;
+                let flag = if opcode == Opcode::JLT { LESS_FLAG } else { EQUAL_FLAG };
+                if self.flags & (1 << flag) != 0 {
+                    self.pc = target;
+                    return Ok(true);
+                }
+            }
+            Opcode::CALL => {
+                let target = self.jump_target(instruction, program_len)?;
+                if self.call_stack.len() >= self.max_call_depth {
+                    return Err(VmError::CallDepthExceeded {
+                        pc: self.pc,
+                        max_depth: self.max_call_depth,
+                    });
+                }
+                self.call_stack.push(self.pc + 1);
+                self.pc = target;
+                return Ok(true);
+            }
+            Opcode::RET => {
+                self.pc = self.call_stack.pop().ok_or(VmError::CallStackUnderflow { pc: self.pc })?;
+                return Ok(true);
+            }
             Opcode::HALT => return Ok(false),
-            _ => return Err(format!("Unsupported opcode: {:?}", instruction.opcode)),
         }
 
         self.pc += 1;
         Ok(true)
     }
 
-    pub fn run_program(&mut self, program: &[Instruction], trace_file: &str) -> Result<(), String> {
+    /// Runs `program` on `inputs` until it halts or runs off its end, within the
+    /// limits of `config`, recording the trace and writing its commitment for a
+    /// circuit with `config.bounds` to `trace_file`. Returns the words the program
+    /// wrote to the output tape.
+    pub fn run_program(&mut self, program: &[Instruction], inputs: &InputTapes, trace_file: &str) -> Result<Vec<u32>, VmError> {
+        self.inputs = inputs.clone();
+        let mut steps = 0;
         while let Some(instruction) = program.get(self.pc as usize) {
+            if steps == self.config.max_steps {
+                return Err(VmError::StepLimit {
+                    max_steps: self.config.max_steps,
+                });
+            }
+            let gas_used = self.gas_used.checked_add(self.config.costs.cost(instruction.opcode));
+            let gas_used = gas_used.filter(|&gas| gas <= self.config.gas_limit).ok_or(VmError::OutOfGas {
+                pc: self.pc,
+                limit: self.config.gas_limit,
+            })?;
+
             self.trace.push(self.capture_state());
-            if !self.execute_instruction(instruction)? {
+            self.gas_used = gas_used;
+            steps += 1;
+            if !self.execute_instruction(instruction, program.len())? {
                 break;
             }
         }
         self.trace.push(self.capture_state());
-        self.generate_trace_commitment(trace_file)
-            .map_err(|e| e.to_string())?;
-        Ok(())
+        self.generate_trace_commitment(trace_file, &self.config.bounds)
+            .map_err(|e| VmError::TraceFile {
+                path: trace_file.to_string(),
+                message: e.to_string(),
+            })?;
+        Ok(self.output.clone())
     }
 
-    pub fn generate_trace_commitment(&self, trace_file: &str) -> io::Result<Vec<u8>> {
-        let mut hasher = Sha256::new();
-
-        for state in &self.trace {
-            let serialized = bincode::serialize(state).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            hasher.update(serialized);
-        }
-
-        let hash = hasher.finalize();
-        let hex_hash = hex::encode(&hash);
+    /// Writes the blinded commitment to the trace, padded for a circuit with
+    /// `bounds`, to `trace_file`.
+    pub fn generate_trace_commitment(&self, trace_file: &str, bounds: &CircuitBounds) -> io::Result<Vec<u8>> {
+        let commitment = commitment::trace_commitment(&self.trace, bounds, self.trace_blinding);
+        let commitment = commitment::commitment_to_bytes(commitment);
+        let hex_hash = hex::encode(&commitment);
 
         let mut file = File::create(trace_file)?;
         writeln!(file, "{}", hex_hash)?;
 
-        Ok(hash.to_vec())
+        Ok(commitment)
     }
 }
 
-#[derive(Clone)]
-pub struct ExecutionCircuit {
-    pub initial_state: ProvableState,
-    pub final_state: ProvableState,
-    pub program: Vec<Instruction>,
-    pub trace_commitment: Vec<u8>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::instruction;
 
-impl ConstraintSynthesizer<Fr> for ExecutionCircuit {
-    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
-        // Convert the trace commitment to a field element for use as a public input
-        let trace_commitment_field = convert_commitment_to_field(&self.trace_commitment);
-
-        // Debug: Trace commitment field
-        println!("Trace Commitment Field: {:?}", trace_commitment_field);
-
-        // Create a variable for the public input
-        let trace_commitment_var = cs.new_input_variable(|| Ok(trace_commitment_field))?;
-
-        // Enforce that the public input matches the expected trace commitment
-        cs.enforce_constraint(
-            lc!() + trace_commitment_var,
-            lc!() + Variable::One,
-            lc!() + (trace_commitment_field.clone(), Variable::One),
-        )?;
-        println!("Public input constraint added for trace commitment.");
-
-        // Initialize simulated state for circuit constraints
-        let mut simulated_stack = self.initial_state.stack.clone();
-        let mut simulated_heap = self.initial_state.heap.clone();
-        let mut current_pc = self.initial_state.pc;
-
-        println!(
-            "Initial state: PC: {}, Stack: {:?}, Heap: {:?}",
-            current_pc, simulated_stack, simulated_heap
-        );
+    fn run(program: &[Instruction]) -> Result<ProvableVM, VmError> {
+        let mut vm = ProvableVM::new();
+        vm.run_program(program, &InputTapes::default(), "/dev/null")?;
+        Ok(vm)
+    }
 
-        // Process each instruction in the program
-        for (i, instruction) in self.program.iter().enumerate() {
-            println!("Processing instruction {}: {:?}", i, instruction);
-
-            match instruction.opcode {
-                Opcode::PUSH => {
-                    if let Some(value) = instruction.operand {
-                        simulated_stack.push(value);
-                        let value_var = cs.new_witness_variable(|| Ok(Fr::from(value)))?;
-                        println!("PUSH: Value: {}, Stack: {:?}", value, simulated_stack);
-
-                        cs.enforce_constraint(
-                            lc!() + value_var,
-                            lc!() + Variable::One,
-                            lc!() + value_var,
-                        )?;
-                    } else {
-                        panic!("PUSH operation requires an operand but none was provided.");
-                    }
-                    current_pc += 1;
-                }
-                Opcode::POP => {
-                    if simulated_stack.is_empty() {
-                        panic!("POP operation requires at least one element on the stack.");
-                    }
-                    simulated_stack.pop();
-                    println!("POP: Stack: {:?}", simulated_stack);
-                    current_pc += 1;
-                }
-                Opcode::ADD => {
-                    if simulated_stack.len() >= 2 {
-                        let a = simulated_stack.pop().unwrap();
-                        let b = simulated_stack.pop().unwrap();
-                        let result = a + b;
-                        simulated_stack.push(result);
-
-                        let a_var = cs.new_witness_variable(|| Ok(Fr::from(a)))?;
-                        let b_var = cs.new_witness_variable(|| Ok(Fr::from(b)))?;
-                        let result_var = cs.new_witness_variable(|| Ok(Fr::from(result)))?;
-
-                        println!("ADD: a: {}, b: {}, result: {}", a, b, result);
-                        println!("Simulated stack after ADD: {:?}", simulated_stack);
-
-                        cs.enforce_constraint(
-                            lc!() + a_var + b_var,
-                            lc!() + Variable::One,
-                            lc!() + result_var,
-                        )?;
-                    } else {
-                        panic!("ADD operation requires at least two elements on the stack.");
-                    }
-                    current_pc += 1;
-                }
-                Opcode::SUB => {
-                    if simulated_stack.len() >= 2 {
-                        let a = simulated_stack.pop().unwrap();
-                        let b = simulated_stack.pop().unwrap();
-                        let result = b - a;
-                        simulated_stack.push(result);
-
-                        let a_var = cs.new_witness_variable(|| Ok(Fr::from(a)))?;
-                        let b_var = cs.new_witness_variable(|| Ok(Fr::from(b)))?;
-                        let result_var = cs.new_witness_variable(|| Ok(Fr::from(result)))?;
-
-                        println!("SUB: a: {}, b: {}, result: {}", a, b, result);
-                        println!("Simulated stack after SUB: {:?}", simulated_stack);
-
-                        cs.enforce_constraint(
-                            lc!() + b_var - a_var,
-                            lc!() + Variable::One,
-                            lc!() + result_var,
-                        )?;
-                    } else {
-                        panic!("SUB operation requires at least two elements on the stack.");
-                    }
-                    current_pc += 1;
-                }
-                Opcode::STORE => {
-                    if let Some(address) = instruction.operand {
-                        if simulated_stack.is_empty() {
-                            panic!("STORE operation requires a value on the stack.");
-                        }
-                        let value = simulated_stack.pop().unwrap();
-                        simulated_heap.insert(address, value);
-
-                        // Use witness variables for both address and value
-                        let address_var = cs.new_witness_variable(|| Ok(Fr::from(address)))?;
-                        let value_var = cs.new_witness_variable(|| Ok(Fr::from(value)))?;
-
-                        println!("STORE: Address: {}, Value: {}, Updated Heap: {:?}", address, value, simulated_heap);
-
-                        // Enforce that the heap is updated with the correct value at the specified address
-                        cs.enforce_constraint(
-                            lc!() + address_var,
-                            lc!() + Variable::One,
-                            lc!() + address_var, // Address consistency (optional; modify if needed)
-                        )?;
-
-                        cs.enforce_constraint(
-                            lc!() + value_var,
-                            lc!() + Variable::One,
-                            lc!() + value_var, // Value consistency (optional; modify if needed)
-                        )?;
-                    } else {
-                        panic!("STORE operation requires an address operand.");
-                    }
-                    current_pc += 1;
-                }
+    /// Pushes `value`, then leaves 1 on the stack if JZ branched and 2 if it fell through.
+    fn branch_program(value: u32) -> Vec<Instruction> {
+        vec![
+            instruction(Opcode::PUSH, Some(value)),
+            instruction(Opcode::JZ, Some(4)),
+            instruction(Opcode::PUSH, Some(2)),
+            instruction(Opcode::JMP, Some(5)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::HALT, None),
+        ]
+    }
 
-                Opcode::LOAD => {
-                    if let Some(address) = instruction.operand {
-                        if let Some(&value) = simulated_heap.get(&address) {
-                            simulated_stack.push(value);
-
-                            // Create witness variables for address and value
-                            let address_var = cs.new_witness_variable(|| Ok(Fr::from(address)))?;
-                            let value_var = cs.new_witness_variable(|| Ok(Fr::from(value)))?;
-
-                            println!("LOAD: Address: {}, Value: {}, Updated Stack: {:?}", address, value, simulated_stack);
-
-                            // Enforce that the value matches the heap at the specified address
-                            cs.enforce_constraint(
-                                lc!() + address_var,
-                                lc!() + Variable::One,
-                                lc!() + address_var,
-                            )?;
-
-                            cs.enforce_constraint(
-                                lc!() + value_var,
-                                lc!() + Variable::One,
-                                lc!() + value_var,
-                            )?;
-                        } else {
-                            panic!(
-                                "LOAD operation requires a valid address in the heap. Address: {}, Heap: {:?}",
-                                address, simulated_heap
-                            );
-                        }
-                    } else {
-                        panic!("LOAD operation requires an address operand.");
-                    }
-                    current_pc += 1;
-                }
+    #[test]
+    fn jumps_follow_their_branches() {
+        // JZ pops its condition and branches on zero
+        let taken = run(&branch_program(0)).unwrap();
+        assert_eq!(taken.stack, vec![1]);
+        let pcs: Vec<u32> = taken.trace.iter().map(|state| state.pc).collect();
+        assert_eq!(pcs, vec![0, 1, 4, 5, 5]);
+
+        // Anything else falls through, and JMP always jumps
+        let not_taken = run(&branch_program(7)).unwrap();
+        assert_eq!(not_taken.stack, vec![2]);
+        let pcs: Vec<u32> = not_taken.trace.iter().map(|state| state.pc).collect();
+        assert_eq!(pcs, vec![0, 1, 2, 3, 5, 5]);
+
+        // JEQ branches on the equal flag left by a comparison
+        let equal_program = |value| {
+            vec![
+                instruction(Opcode::PUSH, Some(2)),
+                instruction(Opcode::PUSH, Some(value)),
+                instruction(Opcode::EQ, None),
+                instruction(Opcode::JEQ, Some(5)),
+                instruction(Opcode::PUSH, Some(0)),
+                instruction(Opcode::HALT, None),
+            ]
+        };
+        assert_eq!(run(&equal_program(2)).unwrap().stack, vec![1]);
+        assert_eq!(run(&equal_program(3)).unwrap().stack, vec![0, 0]);
+    }
 
-                Opcode::HALT => {
-                    cs.enforce_constraint(
-                        lc!() + Variable::One,
-                        lc!() + Variable::One,
-                        lc!() + Variable::One,
-                    )?;
-                    println!("HALT: Execution stopped.");
-                    break;
-                }
-                _ => panic!("Unsupported or invalid opcode encountered."),
-            }
+    #[test]
+    fn jumps_outside_the_program_are_refused() {
+        for opcode in [Opcode::JMP, Opcode::JZ] {
+            let program = vec![instruction(Opcode::PUSH, Some(0)), instruction(opcode, Some(2))];
+            assert_eq!(
+                run(&program).err(),
+                Some(VmError::InvalidJump {
+                    pc: 1,
+                    opcode,
+                    target: 2,
+                    program_len: 2
+                })
+            );
         }
 
-        println!(
-            "Final state: PC: {}, Stack: {:?}, Heap: {:?}",
-            current_pc, simulated_stack, simulated_heap
+        // The target is checked even when JZ would fall through
+        let program = vec![instruction(Opcode::PUSH, Some(1)), instruction(Opcode::JZ, Some(9))];
+        assert!(matches!(run(&program), Err(VmError::InvalidJump { target: 9, .. })));
+
+        let program = vec![instruction(Opcode::JMP, None)];
+        assert_eq!(
+            run(&program).err(),
+            Some(VmError::MissingOperand { pc: 0, opcode: Opcode::JMP })
         );
+    }
 
-        // Final stack consistency check
-        if !simulated_stack.is_empty() {
-            let final_stack_var = cs.new_witness_variable(|| Ok(Fr::from(simulated_stack[0])))?;
-            let expected_stack_var = cs.new_witness_variable(|| Ok(Fr::from(self.final_state.stack[0])))?;
-
-            cs.enforce_constraint(
-                lc!() + final_stack_var,
-                lc!() + Variable::One,
-                lc!() + expected_stack_var,
-            )?;
-            println!(
-                "Final stack consistency constraint: Simulated: {:?}, Expected: {:?}",
-                simulated_stack[0], self.final_state.stack[0]
-            );
-        }
+    #[test]
+    fn call_depth_is_limited() {
+        assert_eq!(
+            run(&[instruction(Opcode::RET, None)]).err(),
+            Some(VmError::CallStackUnderflow { pc: 0 })
+        );
 
-        Ok(())
+        // The outer subroutine at 3 calls the inner one at 2
+        let program = vec![
+            instruction(Opcode::CALL, Some(3)),
+            instruction(Opcode::HALT, None),
+            instruction(Opcode::RET, None),
+            instruction(Opcode::CALL, Some(2)),
+            instruction(Opcode::RET, None),
+        ];
+        let vm = run(&program).unwrap();
+        assert_eq!(vm.trace.iter().map(|state| state.call_stack.len()).max(), Some(2));
+
+        let mut vm = ProvableVM::new();
+        vm.max_call_depth = 1;
+        assert_eq!(
+            vm.run_program(&program, &InputTapes::default(), "/dev/null"),
+            Err(VmError::CallDepthExceeded { pc: 3, max_depth: 1 })
+        );
     }
 
+    #[test]
+    fn runs_are_limited() {
+        // STORE costs 2, one more than the gas left after the first three instructions
+        let program = vec![
+            instruction(Opcode::PUSH, Some(10)),
+            instruction(Opcode::PUSH, Some(20)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::STORE, Some(0)),
+            instruction(Opcode::HALT, None),
+        ];
+        let mut vm = ProvableVM::new();
+        vm.config.gas_limit = 4;
+        assert_eq!(
+            vm.run_program(&program, &InputTapes::default(), "/dev/null"),
+            Err(VmError::OutOfGas { pc: 3, limit: 4 })
+        );
+        assert_eq!(vm.gas_used, 3);
+
+        let mut vm = ProvableVM::new();
+        vm.config.max_steps = 10;
+        let spin = vec![instruction(Opcode::JMP, Some(0))];
+        assert_eq!(
+            vm.run_program(&spin, &InputTapes::default(), "/dev/null"),
+            Err(VmError::StepLimit { max_steps: 10 })
+        );
+        assert_eq!(vm.trace.len(), 10);
+    }
+
+    #[test]
+    fn faults_carry_their_context() {
+        // A failing instruction leaves the stack as it found it
+        let program = vec![instruction(Opcode::PUSH, Some(7)), instruction(Opcode::ADD, None)];
+        let mut vm = ProvableVM::new();
+        let err = vm.run_program(&program, &InputTapes::default(), "/dev/null").unwrap_err();
+        assert_eq!(
+            err,
+            VmError::StackUnderflow {
+                pc: 1,
+                opcode: Opcode::ADD,
+                needed: 2,
+                found: 1
+            }
+        );
+        assert_eq!(err.to_string(), "ADD at pc 1 requires 2 elements on the stack, found 1");
+        assert_eq!(vm.stack, vec![7]);
+
+        let program = vec![
+            instruction(Opcode::PUSH, Some(7)),
+            instruction(Opcode::PUSH, Some(0)),
+            instruction(Opcode::DIVU, None),
+        ];
+        let mut vm = ProvableVM::new();
+        assert_eq!(
+            vm.run_program(&program, &InputTapes::default(), "/dev/null"),
+            Err(VmError::DivisionByZero { pc: 2, opcode: Opcode::DIVU })
+        );
+        assert_eq!(vm.stack, vec![7, 0]);
+
+        let mut vm = ProvableVM::new();
+        assert_eq!(
+            vm.run_program(&[instruction(Opcode::JNZ, Some(4))], &InputTapes::default(), "/dev/null"),
+            Err(VmError::InvalidJump {
+                pc: 0,
+                opcode: Opcode::JNZ,
+                target: 4,
+                program_len: 1
+            })
+        );
+    }
 }
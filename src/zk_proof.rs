@@ -1,59 +1,467 @@
-use crate::ProvableVM;
 use ark_bls12_381::{Bls12_381, Fr};
 use ark_groth16::{Groth16, Proof, ProvingKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::fs::File;
-use std::io;
+use std::io::{self, BufReader};
 use ark_groth16::r1cs_to_qap::LibsnarkReduction;
-use ark_serialize::CanonicalDeserialize;
-use ark_snark::SNARK;
+use ark_relations::r1cs::SynthesisError;
+use ark_serialize::{CanonicalDeserialize, SerializationError};
+use ark_snark::{CircuitSpecificSetupSNARK, SNARK};
 use rand_chacha::ChaCha20Rng;
-use rand_core::SeedableRng;
-use crate::vm::{ExecutionCircuit};
+use rand_core::{CryptoRng, RngCore, SeedableRng};
+use crate::circuit::{CircuitBounds, CircuitError, CircuitShape, ExecutionCircuit};
+use crate::program_loader::program_hash;
+use crate::vm::{Instruction, ProvableVM};
+use crate::public_inputs::{PublicInputLayout, PublicInputs};
 use ark_serialize::CanonicalSerialize;
 
-pub fn verify_proof(vk: &VerifyingKey<Bls12_381>, proof_file: &str, public_input: &[Fr]) -> bool {
-    let proof = File::open(proof_file)
-        .ok()
-        .and_then(|mut file| Proof::deserialize_compressed(&mut file).ok());
+/// What a proof file holds: the compressed proof and the shape of the circuit it
+/// was generated for.
+#[derive(Serialize, Deserialize)]
+struct ProofBundle {
+    shape: CircuitShape,
+    proof: Vec<u8>,
+}
+
+/// Verifies the proof in `proof_file` with `vk`, set up for a circuit of `shape`.
+/// A key that does not take the public inputs of `shape`, or a proof generated for
+/// a circuit of another shape, is refused before the proof is checked, since it
+/// cannot verify under `vk`.
+pub fn verify_proof(
+    vk: &VerifyingKey<Bls12_381>,
+    shape: &CircuitShape,
+    proof_file: &str,
+    public_inputs: &PublicInputs,
+) -> Result<bool, VerifyError> {
+    if vk.gamma_abc_g1.len() != shape.instance_variables {
+        return Err(VerifyError::CircuitMismatch {
+            inputs: vk.gamma_abc_g1.len(),
+            shape: Box::new(shape.clone()),
+        });
+    }
+    let file = File::open(proof_file).map_err(VerifyError::Io)?;
+    let bundle: ProofBundle =
+        bincode::deserialize_from(BufReader::new(file)).map_err(|e| VerifyError::Format(e.to_string()))?;
+    if bundle.shape != *shape {
+        return Err(VerifyError::WrongCircuit {
+            proof: Box::new(bundle.shape),
+            key: Box::new(shape.clone()),
+        });
+    }
+
+    let proof = Proof::deserialize_compressed(bundle.proof.as_slice()).map_err(|e| VerifyError::Format(e.to_string()))?;
+    let public_input: Vec<Fr> = public_inputs.to_field_elements(&shape.bounds);
+    Ok(Groth16::<Bls12_381>::verify(vk, &public_input, &proof).unwrap_or(false))
+}
+
+/// Verifies a proof of running `program`. The claimed program hash is checked
+/// against `program` before the proof itself, so a proof for one program is never
+/// accepted for another.
+pub fn verify_program_proof(
+    vk: &VerifyingKey<Bls12_381>,
+    shape: &CircuitShape,
+    proof_file: &str,
+    program: &[Instruction],
+    public_inputs: &PublicInputs,
+) -> Result<bool, VerifyError> {
+    let hash = program_hash(program);
+    if public_inputs.program_hash != hash {
+        return Err(VerifyError::ProgramMismatch {
+            claimed: public_inputs.program_hash.clone(),
+            program: hash,
+        });
+    }
+    verify_proof(vk, shape, proof_file, public_inputs)
+}
+
+/// Why a proof could not be checked at all, as opposed to being invalid.
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The proof file could not be read.
+    Io(io::Error),
+    /// The file is not a proof file, or the proof in it cannot be decoded.
+    Format(String),
+    /// The verifying key takes `inputs` public inputs, counting the constant one,
+    /// which is not what the circuit of `shape` exposes.
+    CircuitMismatch { inputs: usize, shape: Box<CircuitShape> },
+    /// The proof was generated for another circuit than the key was set up for.
+    WrongCircuit { proof: Box<CircuitShape>, key: Box<CircuitShape> },
+    /// The public inputs claim a run of another program than the one given.
+    ProgramMismatch { claimed: Vec<u8>, program: Vec<u8> },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Io(e) => write!(f, "Failed to read the proof: {}", e),
+            VerifyError::Format(e) => write!(f, "Not a proof file: {}", e),
+            VerifyError::CircuitMismatch { inputs, shape } => write!(
+                f,
+                "Verifying key takes {} public inputs, its circuit {}\n  circuit: {}",
+                inputs, shape.instance_variables, shape
+            ),
+            VerifyError::WrongCircuit { proof, key } => write!(
+                f,
+                "Proof was generated for a different circuit\n  proof: {}\n  key:   {}",
+                proof, key
+            ),
+            VerifyError::ProgramMismatch { claimed, program } => write!(
+                f,
+                "Proof was generated for a different program\n  proof:   {}\n  program: {}",
+                hex::encode(claimed),
+                hex::encode(program)
+            ),
+        }
+    }
+}
 
-    if let Some(proof) = proof {
-        Groth16::<Bls12_381>::verify(vk, public_input, &proof).is_ok()
-    } else {
-        false
+impl std::error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VerifyError::Io(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
+/// Why keys or a proof could not be generated.
+#[derive(Debug)]
+pub enum ProverError {
+    /// The trace commitment could not be written.
+    Trace(io::Error),
+    /// The program or its execution does not fit the circuit.
+    Circuit(CircuitError),
+    /// Groth16 setup failed.
+    Setup(SynthesisError),
+    /// Synthesising the circuit or proving it failed.
+    Synthesis(SynthesisError),
+    /// The proving key does not fit the shape it is recorded with.
+    WrongKey { key: Box<CircuitShape> },
+    /// The execution was charged by another cost table than the circuit of the key.
+    WrongCosts { key: Box<CircuitShape> },
+    /// The proof could not be serialized.
+    Serialization(SerializationError),
+    /// The proof file could not be created.
+    Io(io::Error),
+}
+
+impl fmt::Display for ProverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProverError::Trace(e) => write!(f, "Failed to write the trace commitment: {}", e),
+            ProverError::Circuit(e) => write!(f, "Execution does not fit the circuit: {}", e),
+            ProverError::Setup(e) => write!(f, "Key setup failed: {}", e),
+            ProverError::Synthesis(e) => write!(f, "Proving failed: {}", e),
+            ProverError::WrongKey { key } => write!(f, "Proving key does not fit its circuit\n  key: {}", key),
+            ProverError::WrongCosts { key } => write!(
+                f,
+                "Execution was charged by different gas costs than the circuit of the key\n  key: {}",
+                key
+            ),
+            ProverError::Serialization(e) => write!(f, "Failed to serialize the proof: {}", e),
+            ProverError::Io(e) => write!(f, "Failed to write the proof: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProverError::Trace(e) | ProverError::Io(e) => Some(e),
+            ProverError::Circuit(e) => Some(e),
+            ProverError::Setup(e) | ProverError::Synthesis(e) => Some(e),
+            ProverError::Serialization(e) => Some(e),
+            ProverError::WrongKey { .. } | ProverError::WrongCosts { .. } => None,
+        }
+    }
+}
+
+impl From<CircuitError> for ProverError {
+    fn from(e: CircuitError) -> Self {
+        ProverError::Circuit(e)
+    }
+}
+
+/// Sets up a key pair proving every program within `bounds` and exposing
+/// `public_layout`, from the blank circuit.
+pub fn setup<R: RngCore + CryptoRng>(
+    bounds: CircuitBounds,
+    public_layout: &PublicInputLayout,
+    rng: &mut R,
+) -> Result<(ProvingKey<Bls12_381>, VerifyingKey<Bls12_381>), ProverError> {
+    let circuit = ExecutionCircuit::blank(bounds, public_layout.clone())?;
+    Groth16::<Bls12_381>::setup(circuit, rng).map_err(ProverError::Setup)
+}
+
+/// Shape of the circuit keys for `bounds` and `public_layout` are set up for.
+pub fn circuit_shape(bounds: CircuitBounds, public_layout: &PublicInputLayout) -> Result<CircuitShape, ProverError> {
+    ExecutionCircuit::blank(bounds, public_layout.clone())?
+        .shape()
+        .map_err(ProverError::Synthesis)
+}
+
+/// Proves the execution `vm` recorded with `pk`, set up for the circuit of
+/// `shape`, and writes it with `shape` to `proof_file`. The circuit takes its
+/// bounds, layout and gas costs from `shape`, so it is not synthesised to compare
+/// it with the key. Fails without proving if `pk` does not fit `shape` or `vm`
+/// charged gas by another cost table.
 pub fn generate_proof(
     vm: &ProvableVM,
-    program: &[crate::vm::Instruction], // Instruction is in vm.rs
+    program: &[Instruction],
     trace_file: &str,
     proof_file: &str,
     pk: &ProvingKey<Bls12_381>,
-) -> io::Result<()> {
-    let trace_commitment = vm.generate_trace_commitment(trace_file)?;
-    let initial_state = vm.trace.first().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::InvalidData, "Trace is empty, no initial state")
-    })?;
-    let final_state = vm.trace.last().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::InvalidData, "Trace is empty, no final state")
-    })?;
-
-    let circuit = ExecutionCircuit {
-        initial_state: initial_state.clone(),
-        final_state: final_state.clone(),
-        program: Vec::from(program),
-        trace_commitment,
-    };
+    shape: &CircuitShape,
+) -> Result<PublicInputs, ProverError> {
+    if pk.vk.gamma_abc_g1.len() != shape.instance_variables || pk.l_query.len() != shape.witness_variables {
+        return Err(ProverError::WrongKey { key: Box::new(shape.clone()) });
+    }
+    if vm.config.costs != shape.gas_costs {
+        return Err(ProverError::WrongCosts { key: Box::new(shape.clone()) });
+    }
+    vm.generate_trace_commitment(trace_file, &shape.bounds).map_err(ProverError::Trace)?;
+    let mut circuit = ExecutionCircuit::new(
+        shape.bounds,
+        program,
+        &vm.trace,
+        vm.trace_blinding,
+        shape.layout.clone(),
+        vm.memory.clone(),
+        vm.inputs.clone(),
+    )?;
+    circuit.gas_costs = shape.gas_costs.clone();
+    let public_inputs = circuit.public_inputs();
 
     let mut rng = ChaCha20Rng::from_entropy();
     let proof = Groth16::<Bls12_381, LibsnarkReduction>::prove(pk, circuit, &mut rng)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        .map_err(ProverError::Synthesis)?;
 
-    let mut file = File::create(proof_file)?;
+    let mut bytes = Vec::new();
     proof
-        .serialize_compressed(&mut file)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        .serialize_compressed(&mut bytes)
+        .map_err(ProverError::Serialization)?;
+    let file = File::create(proof_file).map_err(ProverError::Io)?;
+    bincode::serialize_into(
+        file,
+        &ProofBundle {
+            shape: shape.clone(),
+            proof: bytes,
+        },
+    )
+    .map_err(|e| ProverError::Io(io::Error::other(e)))?;
+    Ok(public_inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::generate_keys;
+    use crate::test_support::{instruction, temp_path, BOUNDS};
+    use crate::vm::{InputTapes, MemoryModel, Opcode};
+    use std::collections::BTreeMap;
+
+    fn run(program: &[Instruction], trace_file: &str) -> ProvableVM {
+        let mut vm = ProvableVM::new();
+        vm.run_program(program, &InputTapes::default(), trace_file).unwrap();
+        vm
+    }
+
+    #[test]
+    fn one_key_pair_proves_any_program_within_the_bounds() {
+        let program = vec![
+            instruction(Opcode::PUSH, Some(10)),
+            instruction(Opcode::PUSH, Some(20)),
+            instruction(Opcode::ADD, None),
+            instruction(Opcode::STORE, Some(3)),
+            instruction(Opcode::LOAD, Some(3)),
+            instruction(Opcode::DUP, Some(0)),
+            instruction(Opcode::WRITE, None),
+            instruction(Opcode::HALT, None),
+        ];
+        let other_program = vec![
+            instruction(Opcode::PUSH, Some(7)),
+            instruction(Opcode::JZ, Some(3)),
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::HALT, None),
+        ];
+        let trace_file = temp_path("provable-vm-public-inputs.trace");
+        let proof_file = temp_path("provable-vm-public-inputs.proof");
+        let other_proof_file = temp_path("provable-vm-public-inputs-other.proof");
+
+        let layout = PublicInputLayout {
+            initial_stack: true,
+            initial_heap: vec![3],
+            final_stack_top: true,
+            final_heap: vec![3],
+            final_pc: true,
+            final_gas: true,
+        };
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let keys = generate_keys(BOUNDS, &layout, &mut rng).unwrap();
+        let (vk, shape) = (&keys.vk, &keys.shape);
+
+        let vm = run(&program, &trace_file);
+        let public_inputs = generate_proof(&vm, &program, &trace_file, &proof_file, &keys.pk, &keys.shape).unwrap();
+        assert_eq!(public_inputs.final_stack_top, Some(Some(30)));
+        assert_eq!(public_inputs.final_heap, vec![(3, Some(30))]);
+        assert_eq!(public_inputs.initial_heap, vec![(3, None)]);
+        assert_eq!(public_inputs.final_pc, Some(7));
+        assert_eq!(public_inputs.output, vec![30]);
+        assert!(verify_proof(vk, shape, &proof_file, &public_inputs).unwrap());
+
+        // The other program starts from a memory image, under the same keys
+        let mut vm = ProvableVM::with_memory(MemoryModel::Image(BTreeMap::from([(3, 4)])));
+        vm.run_program(&other_program, &InputTapes::default(), &trace_file).unwrap();
+        let other_inputs =
+            generate_proof(&vm, &other_program, &trace_file, &other_proof_file, &keys.pk, &keys.shape).unwrap();
+        assert_eq!(other_inputs.final_stack_top, Some(Some(1)));
+        assert_eq!(other_inputs.initial_heap, vec![(3, Some(4))]);
+        assert!(verify_program_proof(vk, shape, &other_proof_file, &other_program, &other_inputs).unwrap());
+
+        let mut wrong_image = other_inputs.clone();
+        wrong_image.memory_image = public_inputs.memory_image.clone();
+        assert!(!verify_proof(vk, shape, &other_proof_file, &wrong_image).unwrap());
+
+        let mut wrong_model = public_inputs.clone();
+        wrong_model.zero_initialized = true;
+        assert!(!verify_proof(vk, shape, &proof_file, &wrong_model).unwrap());
+
+        let mut wrong_result = public_inputs.clone();
+        wrong_result.final_stack_top = Some(Some(31));
+        assert!(!verify_proof(vk, shape, &proof_file, &wrong_result).unwrap());
+
+        let mut wrong_heap = public_inputs.clone();
+        wrong_heap.final_heap = vec![(3, Some(29))];
+        assert!(!verify_proof(vk, shape, &proof_file, &wrong_heap).unwrap());
+
+        for output in [vec![31], vec![], vec![30, 0]] {
+            let mut wrong_output = public_inputs.clone();
+            wrong_output.output = output;
+            assert!(!verify_proof(vk, shape, &proof_file, &wrong_output).unwrap());
+        }
+
+        let mut wrong_pc = public_inputs.clone();
+        wrong_pc.final_pc = Some(4);
+        assert!(!verify_proof(vk, shape, &proof_file, &wrong_pc).unwrap());
+
+        // Neither proof can be passed off as a run of the other program
+        assert!(verify_program_proof(vk, shape, &proof_file, &program, &public_inputs).unwrap());
+        let result = verify_program_proof(vk, shape, &proof_file, &other_program, &public_inputs);
+        assert!(matches!(result, Err(VerifyError::ProgramMismatch { .. })));
+        assert!(!verify_proof(vk, shape, &other_proof_file, &public_inputs).unwrap());
 
-    println!("Proof written to '{}'", proof_file);
-    Ok(())
-}
\ No newline at end of file
+        let mut relabelled = public_inputs;
+        relabelled.program_hash = program_hash(&other_program);
+        assert!(!verify_proof(vk, shape, &proof_file, &relabelled).unwrap());
+
+        // A program outside the bounds is reported, not proven
+        let long_program = vec![instruction(Opcode::HALT, None); BOUNDS.max_program + 1];
+        let vm = run(&long_program, &trace_file);
+        let result = generate_proof(&vm, &long_program, &trace_file, &proof_file, &keys.pk, &keys.shape);
+        assert!(matches!(result, Err(ProverError::Circuit(CircuitError::ProgramTooLong { len: 9, max: 8 }))));
+    }
+
+    #[test]
+    fn factorial_and_gcd_are_proven() {
+        let bounds = CircuitBounds {
+            max_steps: 64,
+            max_stack: 4,
+            max_program: 24,
+            max_call_depth: 1,
+            max_image: 0,
+            max_input: 0,
+            max_output: 1,
+        };
+        let layout = PublicInputLayout {
+            final_stack_top: true,
+            ..PublicInputLayout::default()
+        };
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let keys = generate_keys(bounds, &layout, &mut rng).unwrap();
+
+        for (example, result) in [("examples/factorial.prov", 120), ("examples/gcd.prov", 6)] {
+            let program = crate::program_loader::load_program(example).unwrap();
+            let trace_file = temp_path("provable-vm-examples.trace");
+            let proof_file = temp_path("provable-vm-examples.proof");
+
+            let vm = run(&program, &trace_file);
+            let public_inputs =
+                generate_proof(&vm, &program, &trace_file, &proof_file, &keys.pk, &keys.shape).unwrap();
+            assert_eq!(public_inputs.final_stack_top, Some(Some(result)));
+            assert!(verify_program_proof(&keys.vk, &keys.shape, &proof_file, &program, &public_inputs).unwrap());
+        }
+    }
+
+    #[test]
+    fn proofs_are_bound_to_their_circuit() {
+        let bounds = CircuitBounds {
+            max_steps: 4,
+            max_stack: 2,
+            max_program: 4,
+            max_call_depth: 0,
+            max_image: 0,
+            max_input: 0,
+            max_output: 0,
+        };
+        let layout = PublicInputLayout::default();
+        let other_layout = PublicInputLayout {
+            final_pc: true,
+            ..PublicInputLayout::default()
+        };
+        let program = vec![
+            instruction(Opcode::PUSH, Some(1)),
+            instruction(Opcode::HALT, None),
+        ];
+        let trace_file = temp_path("provable-vm-shapes.trace");
+        let proof_file = temp_path("provable-vm-shapes.proof");
+
+        let shape = circuit_shape(bounds, &layout).unwrap();
+        let other_shape = circuit_shape(bounds, &other_layout).unwrap();
+        assert_eq!(shape, circuit_shape(bounds, &layout).unwrap());
+        assert_eq!(other_shape.instance_variables, shape.instance_variables + 1);
+
+        // The shape changes with the constraints too, not only with the sizes
+        let mut repriced = ExecutionCircuit::blank(bounds, layout.clone()).unwrap();
+        repriced.gas_costs.set(Opcode::PUSH, 3);
+        let repriced_shape = repriced.shape().unwrap();
+        assert_eq!(repriced_shape.constraints, shape.constraints);
+        assert_ne!(repriced_shape, shape);
+
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let keys = generate_keys(bounds, &layout, &mut rng).unwrap();
+        let other_keys = generate_keys(bounds, &other_layout, &mut rng).unwrap();
+        assert_eq!((&keys.shape, &other_keys.shape), (&shape, &other_shape));
+
+        let vm = run(&program, &trace_file);
+        let public_inputs = generate_proof(&vm, &program, &trace_file, &proof_file, &keys.pk, &keys.shape).unwrap();
+        assert!(verify_proof(&keys.vk, &shape, &proof_file, &public_inputs).unwrap());
+        let result = verify_proof(&other_keys.vk, &other_shape, &proof_file, &public_inputs);
+        assert!(matches!(result, Err(VerifyError::WrongCircuit { proof, key }) if *proof == shape && *key == other_shape));
+        let result = verify_proof(&keys.vk, &shape, &temp_path("provable-vm-missing.proof"), &public_inputs);
+        assert!(matches!(result, Err(VerifyError::Io(_))));
+        // A proof that does not decode is not a proof file, rather than an invalid proof
+        let garbled_file = temp_path("provable-vm-garbled.proof");
+        let bundle = ProofBundle {
+            shape: shape.clone(),
+            proof: vec![0xff; 8],
+        };
+        bincode::serialize_into(File::create(&garbled_file).unwrap(), &bundle).unwrap();
+        let result = verify_proof(&keys.vk, &shape, &garbled_file, &public_inputs);
+        assert!(matches!(result, Err(VerifyError::Format(_))));
+        // A verifying key paired with the shape of another circuit is refused up front
+        let result = verify_proof(&keys.vk, &other_shape, &proof_file, &public_inputs);
+        assert!(matches!(result, Err(VerifyError::CircuitMismatch { inputs, .. }) if inputs == shape.instance_variables));
+
+        // A key recorded with the shape of another circuit is refused before proving
+        let result = generate_proof(&vm, &program, &trace_file, &proof_file, &keys.pk, &other_shape);
+        assert!(matches!(result, Err(ProverError::WrongKey { key }) if *key == other_shape));
+
+        // So is an execution charged by another cost table than the key's circuit
+        let mut vm = ProvableVM::new();
+        vm.config.costs.set(Opcode::PUSH, 3);
+        vm.run_program(&program, &InputTapes::default(), &trace_file).unwrap();
+        let result = generate_proof(&vm, &program, &trace_file, &proof_file, &keys.pk, &keys.shape);
+        assert!(matches!(result, Err(ProverError::WrongCosts { key }) if *key == shape));
+    }
+}
@@ -0,0 +1,78 @@
+use std::process::{Command, Output};
+
+fn provable_vm(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_provable-vm"))
+        .args(args)
+        .current_dir(std::env::temp_dir())
+        .output()
+        .unwrap()
+}
+
+fn example(name: &str) -> String {
+    format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[test]
+fn run_prints_the_trace() {
+    let output = provable_vm(&["run", &example("program.prov"), "--trace", "provable-vm-cli.trace"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("stack [35]"));
+    assert!(stdout.contains("Output: []"));
+}
+
+#[test]
+fn failures_exit_non_zero() {
+    assert_eq!(provable_vm(&[]).status.code(), Some(2));
+    assert_eq!(provable_vm(&["run"]).status.code(), Some(2));
+    assert_eq!(provable_vm(&["setup", "--proof", "x"]).status.code(), Some(2));
+    assert_eq!(provable_vm(&["run", "x.prov", "--bogus", "1"]).status.code(), Some(2));
+    assert_eq!(provable_vm(&["run", "x.prov", "--input", "3,,4"]).status.code(), Some(2));
+    assert_eq!(provable_vm(&["run", "missing.prov"]).status.code(), Some(1));
+    assert_eq!(
+        provable_vm(&["verify", "--vk", "missing.vk", "--public", "missing.public"]).status.code(),
+        Some(1)
+    );
+}
+
+#[test]
+fn proofs_verify_end_to_end() {
+    let bounds = ["--bounds", "8,2,8,0,0,0,0"];
+    let files = ["--pk", "provable-vm-cli.pk", "--vk", "provable-vm-cli.vk"];
+    let proof = ["--proof", "provable-vm-cli.proof", "--public", "provable-vm-cli.public"];
+    for file in [files[1], files[3], proof[1], proof[3], "provable-vm-cli-tampered.public"] {
+        let _ = std::fs::remove_file(std::env::temp_dir().join(file));
+    }
+    let program = example("program.prov");
+    let prove = [&["prove", program.as_str()], &files[..2], &proof[..]].concat();
+
+    // Proving never sets up keys itself
+    let output = provable_vm(&prove);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("run `setup` first"));
+
+    assert_eq!(provable_vm(&[&["setup"], &files[..], &bounds[..]].concat()).status.code(), Some(0));
+    // Existing keys are only replaced on request
+    assert_eq!(provable_vm(&[&["setup"], &files[..], &bounds[..]].concat()).status.code(), Some(1));
+    assert_eq!(
+        provable_vm(&[&["setup", "--force"], &files[..], &bounds[..]].concat()).status.code(),
+        Some(0)
+    );
+
+    assert_eq!(provable_vm(&prove).status.code(), Some(0));
+    let verify = [&["verify", program.as_str()], &files[2..], &proof[..]].concat();
+    let output = provable_vm(&verify);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Output: []"));
+    assert!(stdout.contains("Proof is valid!"));
+
+    // A public file claiming another result is refused
+    let public = std::env::temp_dir().join(proof[3]);
+    let mut tampered = provable_vm::PublicInputs::load(public.to_str().unwrap()).unwrap();
+    tampered.final_stack_top = Some(Some(36));
+    let tampered_path = std::env::temp_dir().join("provable-vm-cli-tampered.public");
+    tampered.save(tampered_path.to_str().unwrap()).unwrap();
+    let verify = [&["verify"], &files[2..], &proof[..2], &["--public", "provable-vm-cli-tampered.public"]].concat();
+    assert_eq!(provable_vm(&verify).status.code(), Some(1));
+}
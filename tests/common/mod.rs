@@ -0,0 +1,18 @@
+//! Helpers shared by the integration tests, mirroring the crate's unit test support.
+
+use provable_vm::CircuitBounds;
+
+/// Bounds small enough to set up keys quickly, large enough for `examples/program.prov`.
+pub const BOUNDS: CircuitBounds = CircuitBounds {
+    max_steps: 16,
+    max_stack: 4,
+    max_program: 8,
+    max_call_depth: 2,
+    max_image: 1,
+    max_input: 2,
+    max_output: 2,
+};
+
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir().join(name).to_str().unwrap().to_string()
+}
@@ -0,0 +1,69 @@
+use provable_vm::assembler::{load_program, program_hash};
+use provable_vm::keys::generate_keys;
+use provable_vm::prover::{generate_proof, ProverError};
+use provable_vm::verifier::{verify_program_proof, verify_proof};
+use provable_vm::vm::InputTapes;
+use provable_vm::{CircuitError, Opcode, ProvableVM, PublicInputLayout, VmError};
+use rand_chacha::ChaCha20Rng;
+use rand_core::SeedableRng;
+
+mod common;
+
+use common::{temp_path, BOUNDS};
+
+#[test]
+fn assembled_programs_run() {
+    let program = load_program("examples/countdown.prov").unwrap();
+    assert_eq!(program[0].opcode, Opcode::PUSH);
+    assert_eq!(program_hash(&program), program_hash(&load_program("examples/countdown.prov").unwrap()));
+
+    let mut vm = ProvableVM::new();
+    let output = vm
+        .run_program(&program, &InputTapes::default(), &temp_path("provable-vm-lib-run.trace"))
+        .unwrap();
+    assert!(output.is_empty());
+    assert_eq!(vm.heap.get(&0), Some(&0));
+    assert_eq!(vm.trace.last().unwrap().pc, 9);
+}
+
+#[test]
+fn faults_are_typed() {
+    let program = load_program("examples/program.prov").unwrap();
+    let mut vm = ProvableVM::new();
+    vm.config.gas_limit = 2;
+    let err = vm
+        .run_program(&program, &InputTapes::default(), &temp_path("provable-vm-lib-fault.trace"))
+        .unwrap_err();
+    assert_eq!(err, VmError::OutOfGas { pc: 2, limit: 2 });
+    let _: &dyn std::error::Error = &err;
+}
+
+#[test]
+fn proofs_verify_through_the_library() {
+    let trace_file = temp_path("provable-vm-lib.trace");
+    let proof_file = temp_path("provable-vm-lib.proof");
+    let layout = PublicInputLayout {
+        final_stack_top: true,
+        ..PublicInputLayout::default()
+    };
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let keys = generate_keys(BOUNDS, &layout, &mut rng).unwrap();
+
+    let program = load_program("examples/program.prov").unwrap();
+    let mut vm = ProvableVM::new();
+    vm.run_program(&program, &InputTapes::default(), &trace_file).unwrap();
+    let public_inputs = generate_proof(&vm, &program, &trace_file, &proof_file, &keys.pk, &keys.shape).unwrap();
+    assert_eq!(public_inputs.final_stack_top, Some(Some(35)));
+    assert!(verify_program_proof(&keys.vk, &keys.shape, &proof_file, &program, &public_inputs).unwrap());
+
+    let mut wrong_result = public_inputs;
+    wrong_result.final_stack_top = Some(Some(36));
+    assert!(!verify_proof(&keys.vk, &keys.shape, &proof_file, &wrong_result).unwrap());
+
+    // gcd needs more instructions than the circuit holds
+    let gcd = load_program("examples/gcd.prov").unwrap();
+    let mut vm = ProvableVM::new();
+    vm.run_program(&gcd, &InputTapes::default(), &trace_file).unwrap();
+    let result = generate_proof(&vm, &gcd, &trace_file, &proof_file, &keys.pk, &keys.shape);
+    assert!(matches!(result, Err(ProverError::Circuit(CircuitError::ProgramTooLong { .. }))));
+}